@@ -0,0 +1,73 @@
+// webp vs avif for static images, at the settings `process` uses by default.
+// run with `cargo bench --bench encode`, output sizes are printed once up front
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+// keep in sync with DEFAULT_WEBP_QUALITY, DEFAULT_AVIF_QUALITY and AVIF_SPEED in process.rs
+const WEBP_QUALITY: f32 = 90.0;
+const AVIF_QUALITY: f32 = 70.0;
+const AVIF_SPEED: u8 = 6;
+
+// smooth gradients with some hard edges and noise, closer to a real avatar than a flat color
+fn test_image(size: u32) -> image::RgbaImage {
+    let mut state = 0x2545f491u32;
+    image::RgbaImage::from_fn(size, size, |x, y| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let noise = (state % 16) as u8;
+        let block = if (x / 64 + y / 64) % 2 == 0 { 0 } else { 64 };
+        image::Rgba([
+            (x * 255 / size) as u8 / 2 + block + noise,
+            (y * 255 / size) as u8 / 2 + noise,
+            ((x + y) * 255 / (2 * size)) as u8 / 2 + block,
+            255,
+        ])
+    })
+}
+
+fn encode_webp(image: &image::RgbaImage) -> Vec<u8> {
+    webp::Encoder::from_rgba(image.as_raw(), image.width(), image.height())
+        .encode(WEBP_QUALITY)
+        .to_vec()
+}
+
+fn encode_avif(image: &image::RgbaImage) -> Vec<u8> {
+    let pixels: Vec<ravif::RGBA8> = image
+        .pixels()
+        .map(|p| ravif::RGBA8::new(p.0[0], p.0[1], p.0[2], p.0[3]))
+        .collect();
+    ravif::Encoder::new()
+        .with_quality(AVIF_QUALITY)
+        .with_speed(AVIF_SPEED)
+        .encode_rgba(ravif::Img::new(pixels.as_slice(), image.width() as usize, image.height() as usize))
+        .unwrap()
+        .avif_file
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    // avif at 1024x1024 takes a while per iteration
+    group.sample_size(10);
+
+    // avatar and banner sizes
+    for size in [512, 1024] {
+        let image = test_image(size);
+        println!(
+            "{0}x{0}: webp {1}K, avif {2}K",
+            size,
+            encode_webp(&image).len() / 1024,
+            encode_avif(&image).len() / 1024
+        );
+
+        group.bench_with_input(BenchmarkId::new("webp", size), &image, |b, image| {
+            b.iter(|| encode_webp(image))
+        });
+        group.bench_with_input(BenchmarkId::new("avif", size), &image, |b, image| {
+            b.iter(|| encode_avif(image))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+// sha256 vs blake3 over typical stored image sizes, i.e. what `hash_algorithm` trades.
+// run with `cargo bench --bench hash`
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sha2::{Digest, Sha256};
+
+// same as `hash::Hash::sha256` / `hash::Hash::blake3`
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn blake3(data: &[u8]) -> [u8; 32] {
+    blake3::hash(data).into()
+}
+
+fn hash(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash");
+
+    // a small avatar, a large avatar, a banner, and the upper end of what a gif can be
+    for size in [64 * 1024, 512 * 1024, 2 * 1024 * 1024, 8 * 1024 * 1024] {
+        let data: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("sha256", size), &data, |b, data| {
+            b.iter(|| sha256(data))
+        });
+        group.bench_with_input(BenchmarkId::new("blake3", size), &data, |b, data| {
+            b.iter(|| blake3(data))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hash);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+use std::process::Command;
+
+// `GIT_COMMIT` for the startup log. docker builds don't get the .git directory,
+// so the image build passes it in as a build arg instead
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+
+    let commit = std::env::var("GIT_COMMIT").ok().or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "HEAD"]).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+
+    if let Some(commit) = commit.filter(|x| !x.is_empty()) {
+        println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    }
+}
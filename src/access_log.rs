@@ -0,0 +1,46 @@
+use crate::db;
+use dashmap::DashSet;
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+// image ids accessed since the last flush. writing `last_accessed_at` on every
+// access would turn every read into a write, so they're batched up here instead
+#[derive(Default)]
+pub struct AccessLog {
+    ids: DashSet<String>,
+}
+
+impl AccessLog {
+    pub fn record(&self, id: &str) {
+        if !self.ids.contains(id) {
+            self.ids.insert(id.to_string());
+        }
+    }
+
+    fn take(&self) -> Vec<String> {
+        let ids: Vec<String> = self.ids.iter().map(|x| x.key().clone()).collect();
+        for id in &ids {
+            self.ids.remove(id);
+        }
+        ids
+    }
+}
+
+pub async fn flush_task(log: Arc<AccessLog>, pool: PgPool) {
+    loop {
+        tokio::time::sleep(FLUSH_INTERVAL).await;
+        let ids = log.take();
+        if ids.is_empty() {
+            continue;
+        }
+
+        match db::mark_accessed(&pool, &ids).await {
+            Ok(()) => info!("updated last access time for {} images", ids.len()),
+            Err(e) => error!("error updating last access times: {}", e),
+        }
+    }
+}
@@ -0,0 +1,713 @@
+use crate::db::{CompactResult, ExtendedImageMeta, ImageMeta, NewQueueItem, StaleImageStats};
+use crate::process::{self, ProcessOptions, ProcessTimings, ProcessedFormat};
+use crate::store::{S3ObjectInfo, Storer, UploadProgress};
+use crate::hash::Hash;
+use crate::recent_errors::ErrorRecord;
+use crate::{db, pull, AppState, ConfigView, ImageKind, PKAvatarError};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/config", get(config))
+        .route("/requeue-failed", post(requeue_failed))
+        .route("/requeue-dead", post(requeue_dead))
+        .route("/stale-images", get(stale_images))
+        .route("/compact-queue", post(compact_queue))
+        .route("/test-process", post(test_process))
+        .route("/image/by-filename/:filename", get(images_by_filename))
+        .route("/image/duplicates", get(duplicate_images))
+        .route("/image/large", get(large_images))
+        .route("/image/small", get(small_images))
+        .route("/image/:id/metadata", get(image_metadata))
+        .route("/image/:id/retag", post(retag_image))
+        .route("/image/:id/s3-path", get(image_s3_path))
+        .route("/image/:id/verify", get(verify_image))
+        .route("/stats/by-account", get(stats_by_account))
+        .route("/stats/storage-breakdown", get(storage_breakdown))
+        .route("/orphan-queue-items", get(orphan_queue_items))
+        .route("/hot-attachments", get(hot_attachments))
+        .route("/recent-errors", get(recent_errors))
+        .route("/queue/duplicate-count", get(queue_duplicate_count))
+        .route("/queue/bulk", post(queue_bulk))
+        .route("/uploads", get(uploads_in_progress))
+        .route("/upload/:upload_id/progress", get(upload_progress))
+        .route_layer(middleware::from_fn_with_state(state, require_admin_token))
+}
+
+async fn require_admin_token(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, PKAvatarError> {
+    let provided = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+
+    // no token configured = admin endpoints are disabled entirely
+    match (&state.config.admin_token, provided) {
+        (Some(expected), Some(provided))
+            if bool::from(expected.as_bytes().ct_eq(provided.as_bytes())) =>
+        {
+            Ok(next.run(req).await)
+        }
+        _ => Err(PKAvatarError::Unauthorized),
+    }
+}
+
+async fn config(State(state): State<AppState>) -> Json<ConfigView> {
+    Json(state.config.to_view())
+}
+
+#[derive(Deserialize)]
+pub struct RequeueDeadQuery {
+    limit: Option<i64>,
+}
+
+// items that ran out of attempts (see `migrate::handle_item`), not ones that failed for good
+async fn requeue_dead(
+    State(state): State<AppState>,
+    Query(query): Query<RequeueDeadQuery>,
+) -> Result<Json<RequeueFailedResponse>, PKAvatarError> {
+    let res = db::requeue_dead(&state.pool, query.limit.unwrap_or(1000)).await?;
+    Ok(Json(RequeueFailedResponse {
+        requeued: res.requeued,
+        skipped: res.skipped,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RequeueFailedQuery {
+    error_code: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RequeueFailedResponse {
+    requeued: u64,
+    // already in the queue
+    skipped: u64,
+}
+
+async fn requeue_failed(
+    State(state): State<AppState>,
+    Query(query): Query<RequeueFailedQuery>,
+) -> Result<Json<RequeueFailedResponse>, PKAvatarError> {
+    let res = db::requeue_failed(
+        &state.pool,
+        query.error_code.as_deref(),
+        query.limit.unwrap_or(1000),
+    )
+    .await?;
+    Ok(Json(RequeueFailedResponse {
+        requeued: res.requeued,
+        skipped: res.skipped,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct StaleImagesQuery {
+    days: Option<i32>,
+}
+
+// report only, nothing gets queued or deleted. see `db::get_stale_image_stats` for why
+async fn stale_images(
+    State(state): State<AppState>,
+    Query(query): Query<StaleImagesQuery>,
+) -> Result<Json<StaleImageStats>, PKAvatarError> {
+    Ok(Json(db::get_stale_image_stats(&state.pool, query.days.unwrap_or(365)).await?))
+}
+
+async fn compact_queue(State(state): State<AppState>) -> Result<Json<CompactResult>, PKAvatarError> {
+    Ok(Json(db::compact_queue(&state.pool).await?))
+}
+
+// base64 of anything bigger makes for an unreasonably large json response
+const MAX_TEST_OUTPUT_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct TestProcessRequest {
+    url: String,
+    kind: ImageKind,
+}
+
+#[derive(Serialize)]
+pub struct TestProcessResponse {
+    detected_format: Option<String>,
+    original_content_type: String,
+    original_file_size: usize,
+    original_width: Option<u32>,
+    original_height: Option<u32>,
+    dimensions_exceeded: bool,
+    total_ms: u64,
+    error: Option<String>,
+    output: Option<TestProcessOutput>,
+}
+
+#[derive(Serialize)]
+pub struct TestProcessOutput {
+    format: &'static str,
+    width: u32,
+    height: u32,
+    animated: bool,
+    file_size: usize,
+    timings: ProcessTimings,
+    // only included if under MAX_TEST_OUTPUT_SIZE
+    data_base64: Option<String>,
+}
+
+// runs the full pipeline without touching the database or storage
+async fn test_process(
+    State(state): State<AppState>,
+    Json(req): Json<TestProcessRequest>,
+) -> Result<Json<TestProcessResponse>, PKAvatarError> {
+    let parsed = pull::parse_url(&req.url).map_err(|_| PKAvatarError::InvalidCdnUrl)?;
+    let pulled = state.puller.pull(&parsed, req.kind).await?;
+
+    let (format, dimensions) = process::probe(&pulled.data);
+    let original_file_size = pulled.data.len();
+
+    let time_before = std::time::Instant::now();
+    let result =
+        process::process_async(pulled.data, req.kind, ProcessOptions::new(&state.config)).await;
+    let total_ms = time_before.elapsed().as_millis() as u64;
+
+    let (output, error, dimensions_exceeded) = match result {
+        Ok(encoded) => (
+            Some(TestProcessOutput {
+                format: encoded.format.mime_type(),
+                width: encoded.width,
+                height: encoded.height,
+                animated: encoded.animated,
+                file_size: encoded.data.len(),
+                timings: encoded.timings,
+                data_base64: (encoded.data.len() < MAX_TEST_OUTPUT_SIZE)
+                    .then(|| data_encoding::BASE64.encode(&encoded.data)),
+            }),
+            None,
+            false,
+        ),
+        Err(e) => {
+            let dimensions_exceeded = matches!(e, PKAvatarError::ImageDimensionsTooLarge(_, _));
+            (None, Some(e.to_string()), dimensions_exceeded)
+        }
+    };
+
+    Ok(Json(TestProcessResponse {
+        detected_format: format.map(|x| format!("{:?}", x)),
+        original_content_type: pulled.content_type,
+        original_file_size,
+        original_width: dimensions.map(|x| x.0),
+        original_height: dimensions.map(|x| x.1),
+        dimensions_exceeded,
+        total_ms,
+        error,
+        output,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct ImageMetadataResponse {
+    #[serde(flatten)]
+    image: ExtendedImageMeta,
+    path: String,
+    #[serde(flatten)]
+    s3: S3ObjectInfo,
+}
+
+async fn image_metadata(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ImageMetadataResponse>, PKAvatarError> {
+    let image = db::get_extended_metadata(&state.pool, &id)
+        .await?
+        .ok_or(PKAvatarError::ImageNotFound)?;
+
+    let format = ProcessedFormat::from_mime_type(&image.meta.content_type).unwrap_or(ProcessedFormat::Webp);
+    let path = Storer::path_for_id(&image.meta.id, format);
+    let s3 = state.storer.get_object_info(&path).await?;
+
+    Ok(Json(ImageMetadataResponse { image, path, s3 }))
+}
+
+#[derive(Deserialize)]
+pub struct ByFilenameQuery {
+    limit: Option<i64>,
+}
+
+async fn images_by_filename(
+    State(state): State<AppState>,
+    Path(filename): Path<String>,
+    Query(query): Query<ByFilenameQuery>,
+) -> Result<Json<Vec<ImageMeta>>, PKAvatarError> {
+    Ok(Json(
+        db::get_images_by_filename(&state.pool, &filename, query.limit.unwrap_or(50)).await?,
+    ))
+}
+
+#[derive(Serialize)]
+pub struct DuplicateImagesResponse {
+    attachment_duplicates: Vec<AttachmentDuplicate>,
+}
+
+#[derive(Serialize)]
+pub struct AttachmentDuplicate {
+    attachment_id: String,
+    // oldest first
+    ids: Vec<String>,
+}
+
+async fn duplicate_images(
+    State(state): State<AppState>,
+) -> Result<Json<DuplicateImagesResponse>, PKAvatarError> {
+    let duplicates = db::find_attachment_duplicates(&state.pool).await?;
+    Ok(Json(DuplicateImagesResponse {
+        attachment_duplicates: duplicates
+            .into_iter()
+            .map(|(attachment_id, ids)| AttachmentDuplicate {
+                attachment_id: attachment_id.to_string(),
+                ids,
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct LargeImagesQuery {
+    min_size_bytes: Option<i32>,
+    kind: Option<ImageKind>,
+    limit: Option<i64>,
+}
+
+async fn large_images(
+    State(state): State<AppState>,
+    Query(query): Query<LargeImagesQuery>,
+) -> Result<Json<Vec<ImageMeta>>, PKAvatarError> {
+    // sorted biggest first
+    let images = db::get_images_larger_than(
+        &state.pool,
+        query.min_size_bytes.unwrap_or(100 * 1024),
+        query.kind,
+        query.limit.unwrap_or(50),
+    )
+    .await?;
+    Ok(Json(images))
+}
+
+#[derive(Deserialize)]
+pub struct SmallImagesQuery {
+    max_size_bytes: Option<i32>,
+    kind: Option<ImageKind>,
+    limit: Option<i64>,
+}
+
+async fn small_images(
+    State(state): State<AppState>,
+    Query(query): Query<SmallImagesQuery>,
+) -> Result<Json<Vec<ImageMeta>>, PKAvatarError> {
+    // sorted smallest first
+    let images = db::get_images_smaller_than(
+        &state.pool,
+        query.max_size_bytes.unwrap_or(1024),
+        query.kind,
+        query.limit.unwrap_or(50),
+    )
+    .await?;
+    Ok(Json(images))
+}
+
+#[derive(Serialize)]
+pub struct AccountStatsResponse {
+    account_id: String, // discord ids don't fit in a js number
+    count: i64,
+    total_size: i64,
+}
+
+async fn stats_by_account(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AccountStatsResponse>>, PKAvatarError> {
+    let stats = db::get_top_uploaders(&state.pool, 100).await?;
+    Ok(Json(
+        stats
+            .into_iter()
+            .map(|x| AccountStatsResponse {
+                account_id: x.account_id.to_string(),
+                count: x.count,
+                total_size: x.total_size,
+            })
+            .collect(),
+    ))
+}
+
+// ids are base32, so two characters give 32 * 32 possible prefixes
+const POSSIBLE_PREFIXES: usize = 32 * 32;
+
+#[derive(Serialize)]
+pub struct StorageBreakdownResponse {
+    active_prefixes: usize,
+    possible_prefixes: usize,
+    prefixes: Vec<StoragePrefix>,
+}
+
+#[derive(Serialize)]
+pub struct StoragePrefix {
+    prefix: String,
+    count: i64,
+    total_size: i64,
+}
+
+// the hash is uniformly distributed, so these should all be roughly the same size
+async fn storage_breakdown(
+    State(state): State<AppState>,
+) -> Result<Json<StorageBreakdownResponse>, PKAvatarError> {
+    let prefixes = db::get_storage_prefix_distribution(&state.pool).await?;
+    Ok(Json(StorageBreakdownResponse {
+        active_prefixes: prefixes.len(),
+        possible_prefixes: POSSIBLE_PREFIXES,
+        prefixes: prefixes
+            .into_iter()
+            .map(|(prefix, count, total_size)| StoragePrefix {
+                prefix,
+                count,
+                total_size,
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Serialize)]
+pub struct OrphanQueueItemsResponse {
+    count: usize,
+    urls: Vec<String>,
+}
+
+async fn orphan_queue_items(
+    State(state): State<AppState>,
+) -> Result<Json<OrphanQueueItemsResponse>, PKAvatarError> {
+    let items = db::get_orphan_queue_items(&state.pool).await?;
+    Ok(Json(OrphanQueueItemsResponse {
+        count: items.len(),
+        urls: items.into_iter().map(|(_, url)| url).collect(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct HotAttachmentsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct HotAttachment {
+    attachment_id: String,
+    count: u32,
+}
+
+async fn hot_attachments(
+    State(state): State<AppState>,
+    Query(query): Query<HotAttachmentsQuery>,
+) -> Json<Vec<HotAttachment>> {
+    Json(
+        state
+            .recent_pulls
+            .hottest(query.limit.unwrap_or(20))
+            .into_iter()
+            .map(|(attachment_id, count)| HotAttachment {
+                attachment_id: attachment_id.to_string(),
+                count,
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct RecentErrorsQuery {
+    limit: Option<usize>,
+}
+
+async fn recent_errors(
+    State(state): State<AppState>,
+    Query(query): Query<RecentErrorsQuery>,
+) -> Json<Vec<ErrorRecord>> {
+    Json(state.recent_errors.latest(query.limit.unwrap_or(50)))
+}
+
+#[derive(Deserialize)]
+pub struct RetagRequest {
+    kind: ImageKind,
+}
+
+#[derive(Serialize)]
+pub struct RetagResponse {
+    url: String,
+}
+
+// re-processes an image as a different kind. the old row is kept around, marked as deprecated.
+// works from the original where it can still be fetched, the stored copy has already been downscaled
+async fn retag_image(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<RetagRequest>,
+) -> Result<Json<RetagResponse>, PKAvatarError> {
+    let old = db::get_extended_metadata(&state.pool, &id)
+        .await?
+        .ok_or(PKAvatarError::ImageNotFound)?
+        .meta;
+
+    let data = match fetch_original(&state, &old, req.kind).await {
+        Some(data) => data,
+        None => {
+            let format = ProcessedFormat::from_mime_type(&old.content_type).unwrap_or(ProcessedFormat::Webp);
+            state
+                .storer
+                .fetch(&Storer::path_for_id(&old.id, format))
+                .await?
+        }
+    };
+
+    let encoded =
+        process::process_async(data, req.kind, ProcessOptions::new(&state.config)).await?;
+    let store_res = state.storer.store(&encoded).await?;
+    let final_url = state.url_for_path(&store_res.id, &store_res.path);
+    let attachment_id = old.original_attachment_id;
+
+    // either both rows change or neither, so an image never ends up deprecated by one that isn't there
+    let mut tx = state.pool.begin().await?;
+    db::add_image(
+        &mut *tx,
+        ImageMeta {
+            id: store_res.id.clone(),
+            url: final_url.clone(),
+            content_type: encoded.format.mime_type().to_string(),
+            file_size: encoded.data.len() as i32,
+            width: encoded.width as i32,
+            height: encoded.height as i32,
+            kind: req.kind,
+            uploaded_at: None,
+            encode_quality: encoded.quality_used,
+            hash_algorithm: encoded.hash_algorithm,
+            ..old
+        },
+    )
+    .await?;
+
+    // same output as before (eg. already small enough for both kinds), nothing to deprecate,
+    // but the row is still tagged with the old kind
+    if store_res.id != id {
+        db::deprecate_image(&mut tx, &id, &store_res.id).await?;
+    } else {
+        db::set_kind(&mut tx, &id, req.kind).await?;
+    }
+    tx.commit().await?;
+
+    // /pull would otherwise keep handing out the old image for this attachment
+    if let Some(attachment_id) = attachment_id {
+        state.attachment_cache.remove(attachment_id as u64);
+    }
+
+    Ok(Json(RetagResponse { url: final_url }))
+}
+
+// None if there's no original url, or it can't be pulled anymore (eg. the signed url expired)
+async fn fetch_original(state: &AppState, image: &ImageMeta, kind: ImageKind) -> Option<Vec<u8>> {
+    let parsed = pull::parse_url(image.original_url.as_ref()?).ok()?;
+    match state.puller.pull(&parsed, kind).await {
+        Ok(res) => Some(res.data),
+        Err(e) => {
+            tracing::warn!("couldn't refetch original for {}, retagging the stored copy: {}", image.id, e);
+            None
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DuplicateCountResponse {
+    duplicates_skipped: u64,
+}
+
+async fn queue_duplicate_count() -> Json<DuplicateCountResponse> {
+    Json(DuplicateCountResponse {
+        duplicates_skipped: db::QUEUE_DUPLICATES_SKIPPED.load(Ordering::Relaxed),
+    })
+}
+
+#[derive(Serialize)]
+pub struct S3PathResponse {
+    path: String,
+    full_url: String,
+}
+
+async fn image_s3_path(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<S3PathResponse>, PKAvatarError> {
+    // need the content type to know the extension
+    let image = db::get_extended_metadata(&state.pool, &id)
+        .await?
+        .ok_or(PKAvatarError::ImageNotFound)?
+        .meta;
+
+    let format = ProcessedFormat::from_mime_type(&image.content_type).unwrap_or(ProcessedFormat::Webp);
+    let path = Storer::path_for_id(&image.id, format);
+    Ok(Json(S3PathResponse {
+        full_url: state.url_for_path(&image.id, &path),
+        path,
+    }))
+}
+
+const MAX_BULK_QUEUE_ITEMS: usize = 5000;
+// keeps each insert statement well under postgres' bind parameter limit
+const BULK_QUEUE_CHUNK_SIZE: usize = 500;
+
+#[derive(Deserialize)]
+pub struct BulkQueueRequest {
+    items: Vec<NewQueueItem>,
+}
+
+#[derive(Serialize)]
+pub struct BulkQueueResponse {
+    queued: u64,
+    // already in the queue
+    skipped: u64,
+}
+
+async fn queue_bulk(
+    State(state): State<AppState>,
+    Json(req): Json<BulkQueueRequest>,
+) -> Result<Json<BulkQueueResponse>, PKAvatarError> {
+    if req.items.len() > MAX_BULK_QUEUE_ITEMS {
+        return Err(PKAvatarError::TooManyItems(req.items.len(), MAX_BULK_QUEUE_ITEMS));
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let mut queued = 0;
+    for chunk in req.items.chunks(BULK_QUEUE_CHUNK_SIZE) {
+        queued += db::push_queue_batch(&mut tx, chunk).await?;
+    }
+    tx.commit().await?;
+
+    Ok(Json(BulkQueueResponse {
+        queued,
+        skipped: req.items.len() as u64 - queued,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct VerifyResponse {
+    valid: bool,
+    // what didn't match: "missing", "hash", "file_size" or "dimensions"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mismatch: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actual: Option<String>,
+}
+
+impl VerifyResponse {
+    fn mismatch(what: &'static str, expected: String, actual: String) -> Json<VerifyResponse> {
+        Json(VerifyResponse {
+            valid: false,
+            mismatch: Some(what),
+            expected: Some(expected),
+            actual: Some(actual),
+        })
+    }
+}
+
+// checks the stored object against the database row. the id *is* the hash of the stored bytes
+async fn verify_image(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<VerifyResponse>, PKAvatarError> {
+    let image = db::get_extended_metadata(&state.pool, &id)
+        .await?
+        .ok_or(PKAvatarError::ImageNotFound)?
+        .meta;
+
+    let format = ProcessedFormat::from_mime_type(&image.content_type).unwrap_or(ProcessedFormat::Webp);
+    let path = Storer::path_for_id(&image.id, format);
+    if !state.storer.get_object_info(&path).await?.s3_exists {
+        return Ok(VerifyResponse::mismatch("missing", path, "nothing".to_string()));
+    }
+    let data = state.storer.fetch(&path).await?;
+
+    let actual_hash = Hash::new(image.hash_algorithm, &data).to_string();
+    if actual_hash != image.id {
+        return Ok(VerifyResponse::mismatch("hash", image.id, actual_hash));
+    }
+
+    if data.len() != image.file_size as usize {
+        return Ok(VerifyResponse::mismatch(
+            "file_size",
+            image.file_size.to_string(),
+            data.len().to_string(),
+        ));
+    }
+
+    // only needs the header, no full decode. `image` can't read avif at all, those only get the checks above
+    let expected_dimensions = (image.width as u32, image.height as u32);
+    let (_, dimensions) = process::probe(&data);
+    if !matches!(format, ProcessedFormat::Avif) && dimensions != Some(expected_dimensions) {
+        return Ok(VerifyResponse::mismatch(
+            "dimensions",
+            format!("{}x{}", expected_dimensions.0, expected_dimensions.1),
+            dimensions.map_or("unknown".to_string(), |(w, h)| format!("{}x{}", w, h)),
+        ));
+    }
+
+    Ok(Json(VerifyResponse {
+        valid: true,
+        mismatch: None,
+        expected: None,
+        actual: None,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct UploadProgressResponse {
+    upload_id: Uuid,
+    path: String,
+    #[serde(flatten)]
+    progress: UploadProgress,
+}
+
+// only multipart uploads are tracked, single-request uploads have no progress to speak of
+async fn uploads_in_progress(State(state): State<AppState>) -> Json<Vec<UploadProgressResponse>> {
+    Json(
+        state
+            .storer
+            .uploads_in_progress()
+            .into_iter()
+            .map(|(upload_id, path, progress)| UploadProgressResponse {
+                upload_id,
+                path,
+                progress,
+            })
+            .collect(),
+    )
+}
+
+async fn upload_progress(
+    State(state): State<AppState>,
+    Path(upload_id): Path<Uuid>,
+) -> Result<Json<UploadProgressResponse>, PKAvatarError> {
+    let (path, progress) = state
+        .storer
+        .upload_progress(&upload_id)
+        .ok_or(PKAvatarError::UploadNotFound)?;
+    Ok(Json(UploadProgressResponse {
+        upload_id,
+        path,
+        progress,
+    }))
+}
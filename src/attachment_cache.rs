@@ -0,0 +1,66 @@
+use crate::db::ImageMeta;
+use crate::hash::HashAlgorithm;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+// attachment id -> stored image, so pulls of hot avatars don't all have to ask the database.
+// an entry is around 250 bytes with the lru's overhead (mostly the id, url and content type), so the default size is a couple MB
+pub struct AttachmentCache {
+    // None if the capacity is 0 (disabled)
+    entries: Option<Mutex<LruCache<u64, CachedImage>>>,
+}
+
+// what /pull needs to answer with an existing image
+#[derive(Clone)]
+pub struct CachedImage {
+    pub id: String,
+    pub url: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub file_size: u32,
+    pub original_file_size: Option<u32>,
+    pub hash_algorithm: HashAlgorithm,
+}
+
+impl From<&ImageMeta> for CachedImage {
+    fn from(image: &ImageMeta) -> CachedImage {
+        CachedImage {
+            id: image.id.clone(),
+            url: image.url.clone(),
+            content_type: image.content_type.clone(),
+            width: image.width as u32,
+            height: image.height as u32,
+            file_size: image.file_size as u32,
+            original_file_size: image.original_file_size.map(|x| x as u32),
+            hash_algorithm: image.hash_algorithm,
+        }
+    }
+}
+
+impl AttachmentCache {
+    pub fn new(capacity: usize) -> AttachmentCache {
+        AttachmentCache {
+            entries: NonZeroUsize::new(capacity).map(|x| Mutex::new(LruCache::new(x))),
+        }
+    }
+
+    pub fn get(&self, attachment_id: u64) -> Option<CachedImage> {
+        self.entries.as_ref()?.lock().unwrap().get(&attachment_id).cloned()
+    }
+
+    pub fn insert(&self, attachment_id: u64, image: CachedImage) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().put(attachment_id, image);
+        }
+    }
+
+    pub fn remove(&self, attachment_id: u64) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().pop(&attachment_id);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use crate::store::S3ObjectInfo;
+use crate::Config;
+use anyhow::Context;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const AUTHORIZE_URL: &str = "https://api.backblazeb2.com/b2api/v2/b2_authorize_account";
+
+// auth tokens (and upload urls) are only valid for 24 hours, get new ones a bit before that
+const TOKEN_MAX_AGE: Duration = Duration::from_secs(23 * 60 * 60);
+
+// talks to the native b2 api directly, it's only a handful of json endpoints.
+// uses the same credentials as the s3 config: application_id is the key id, and `bucket` is the bucket *id*
+pub struct B2Backend {
+    client: Client,
+    key_id: String,
+    application_key: String,
+    bucket_id: String,
+    auth: Mutex<Option<B2Auth>>,
+    // an upload url only takes one upload at a time, so every upload checks one out of here
+    // (or gets a new one) and puts it back once it's done with it
+    upload_urls: std::sync::Mutex<Vec<B2UploadUrl>>,
+}
+
+#[derive(Clone)]
+struct B2Auth {
+    token: String,
+    api_url: String,
+    download_url: String,
+    // downloads go by bucket name, not id
+    bucket_name: String,
+    fetched_at: Instant,
+}
+
+#[derive(Clone)]
+struct B2UploadUrl {
+    url: String,
+    token: String,
+    fetched_at: Instant,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizeResponse {
+    account_id: String,
+    authorization_token: String,
+    api_url: String,
+    download_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListBucketsResponse {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    bucket_name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetUploadUrlResponse {
+    upload_url: String,
+    authorization_token: String,
+}
+
+impl B2Backend {
+    pub fn new(config: &Config) -> anyhow::Result<B2Backend> {
+        Ok(B2Backend {
+            client: Client::builder()
+                .connect_timeout(Duration::from_secs(3))
+                .build()
+                .context("error making b2 client")?,
+            key_id: config.s3.application_id.clone(),
+            application_key: config.s3.application_key.clone(),
+            bucket_id: config.s3.bucket.clone(),
+            auth: Mutex::new(None),
+            upload_urls: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+
+    async fn auth(&self) -> anyhow::Result<B2Auth> {
+        let mut auth = self.auth.lock().await;
+        match auth.as_ref() {
+            Some(existing) if existing.fetched_at.elapsed() < TOKEN_MAX_AGE => Ok(existing.clone()),
+            _ => {
+                let new_auth = self.authorize().await?;
+                *auth = Some(new_auth.clone());
+                Ok(new_auth)
+            }
+        }
+    }
+
+    async fn authorize(&self) -> anyhow::Result<B2Auth> {
+        let res = self
+            .client
+            .get(AUTHORIZE_URL)
+            .basic_auth(&self.key_id, Some(&self.application_key))
+            .send()
+            .await?;
+        let authorized: AuthorizeResponse = parse_response(res).await?;
+
+        let res = self
+            .client
+            .post(format!("{}/b2api/v2/b2_list_buckets", authorized.api_url))
+            .header(AUTHORIZATION, &authorized.authorization_token)
+            .body(json!({"accountId": authorized.account_id, "bucketId": self.bucket_id}).to_string())
+            .send()
+            .await?;
+        let buckets: ListBucketsResponse = parse_response(res).await?;
+        let bucket_name = buckets
+            .buckets
+            .into_iter()
+            .next()
+            .map(|x| x.bucket_name)
+            .with_context(|| format!("b2 bucket {} not found", self.bucket_id))?;
+
+        info!("authorized with b2 (bucket {})", bucket_name);
+        Ok(B2Auth {
+            token: authorized.authorization_token,
+            api_url: authorized.api_url,
+            download_url: authorized.download_url,
+            bucket_name,
+            fetched_at: Instant::now(),
+        })
+    }
+
+    // an expired token shows up as a 401, make sure the next request gets a new one
+    async fn handle_unauthorized(&self, status: StatusCode) {
+        if status == StatusCode::UNAUTHORIZED {
+            *self.auth.lock().await = None;
+        }
+    }
+
+    async fn checkout_upload_url(&self) -> anyhow::Result<B2UploadUrl> {
+        {
+            let mut upload_urls = self.upload_urls.lock().unwrap();
+            // expired ones just get dropped on the way
+            while let Some(existing) = upload_urls.pop() {
+                if existing.fetched_at.elapsed() < TOKEN_MAX_AGE {
+                    return Ok(existing);
+                }
+            }
+        }
+
+        let auth = self.auth().await?;
+        let res = self
+            .client
+            .post(format!("{}/b2api/v2/b2_get_upload_url", auth.api_url))
+            .header(AUTHORIZATION, &auth.token)
+            .body(json!({"bucketId": self.bucket_id}).to_string())
+            .send()
+            .await?;
+        self.handle_unauthorized(res.status()).await;
+        let res: GetUploadUrlResponse = parse_response(res).await?;
+
+        Ok(B2UploadUrl {
+            url: res.upload_url,
+            token: res.authorization_token,
+            fetched_at: Instant::now(),
+        })
+    }
+
+    // only for urls that just worked, ones that errored are dropped instead
+    fn return_upload_url(&self, upload_url: B2UploadUrl) {
+        self.upload_urls.lock().unwrap().push(upload_url);
+    }
+
+    pub async fn check(&self) -> anyhow::Result<()> {
+        let auth = self.auth().await?;
+        let res = self
+            .client
+            .post(format!("{}/b2api/v2/b2_list_file_names", auth.api_url))
+            .header(AUTHORIZATION, &auth.token)
+            .body(json!({"bucketId": self.bucket_id, "maxFileCount": 1}).to_string())
+            .send()
+            .await?;
+        self.handle_unauthorized(res.status()).await;
+        if !res.status().is_success() {
+            anyhow::bail!("b2 responded status code {}", res.status());
+        }
+        Ok(())
+    }
+
+    pub async fn upload(&self, path: &str, data: &[u8], content_type: &str) -> anyhow::Result<()> {
+        let sha1 = data_encoding::HEXLOWER.encode(&Sha1::digest(data));
+
+        // upload urls can go bad at any time (b2 says to just get a new one), so retry once
+        for attempt in 0..2 {
+            let upload_url = self.checkout_upload_url().await?;
+            let res = self
+                .client
+                .post(&upload_url.url)
+                .header(AUTHORIZATION, &upload_url.token)
+                .header("X-Bz-File-Name", encode_file_name(path))
+                .header(CONTENT_TYPE, content_type)
+                .header(CONTENT_LENGTH, data.len())
+                .header("X-Bz-Content-Sha1", &sha1)
+                .body(data.to_vec())
+                .send()
+                .await;
+
+            let status = match res {
+                Ok(res) if res.status().is_success() => {
+                    self.return_upload_url(upload_url);
+                    return Ok(());
+                }
+                Ok(res) => res.status(),
+                Err(e) if attempt == 0 => {
+                    warn!("error uploading {} to b2, retrying with a new upload url: {}", path, e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if attempt == 0
+                && (status == StatusCode::UNAUTHORIZED || status == StatusCode::SERVICE_UNAVAILABLE)
+            {
+                warn!("b2 upload url responded {}, retrying with a new one", status);
+                continue;
+            }
+            anyhow::bail!("b2 responded status code {} uploading {}", status, path);
+        }
+        unreachable!()
+    }
+
+    async fn download_request(&self, method: reqwest::Method, path: &str) -> anyhow::Result<reqwest::Response> {
+        let auth = self.auth().await?;
+        let res = self
+            .client
+            .request(
+                method,
+                format!("{}/file/{}/{}", auth.download_url, auth.bucket_name, encode_file_name(path)),
+            )
+            .header(AUTHORIZATION, &auth.token)
+            .send()
+            .await?;
+        self.handle_unauthorized(res.status()).await;
+        Ok(res)
+    }
+
+    pub async fn get_object_info(&self, path: &str) -> anyhow::Result<S3ObjectInfo> {
+        let res = self.download_request(reqwest::Method::HEAD, path).await?;
+        let header = |name: &str| {
+            res.headers()
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+        match res.status() {
+            StatusCode::OK => Ok(S3ObjectInfo {
+                s3_exists: true,
+                s3_size: res.content_length().map(|x| x as i64),
+                s3_content_type: header("content-type"),
+                // milliseconds since the epoch
+                s3_last_modified: header("x-bz-upload-timestamp"),
+            }),
+            StatusCode::NOT_FOUND => Ok(S3ObjectInfo {
+                s3_exists: false,
+                s3_size: None,
+                s3_content_type: None,
+                s3_last_modified: None,
+            }),
+            status => anyhow::bail!("b2 responded status code {}", status),
+        }
+    }
+
+    pub async fn fetch(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let res = self.download_request(reqwest::Method::GET, path).await?;
+        if res.status() != StatusCode::OK {
+            anyhow::bail!("b2 responded status code {}", res.status());
+        }
+        Ok(res.bytes().await?.to_vec())
+    }
+}
+
+async fn parse_response<T: DeserializeOwned>(res: reqwest::Response) -> anyhow::Result<T> {
+    let status = res.status();
+    let body = res.bytes().await?;
+    if !status.is_success() {
+        anyhow::bail!("b2 responded status code {}: {}", status, String::from_utf8_lossy(&body));
+    }
+    serde_json::from_slice(&body).context("invalid b2 response")
+}
+
+// b2 wants file names percent-encoded, but with the slashes left alone
+fn encode_file_name(path: &str) -> String {
+    path.split('/')
+        .map(|x| form_urlencoded::byte_serialize(x.as_bytes()).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -1,10 +1,18 @@
-use crate::ImageKind;
+use crate::hash::HashAlgorithm;
+use crate::process::ProcessedFormat;
+use crate::pull::parse_url;
+use crate::{ImageKind, PKAvatarError};
 use s3::creds::time::OffsetDateTime;
-use serde::Serialize;
-use sqlx::{Executor, FromRow, PgPool, Postgres, Transaction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use sqlx::{Executor, FromRow, PgConnection, PgPool, Postgres, QueryBuilder, Row, Transaction};
 use uuid::Uuid;
 
-#[derive(FromRow)]
+// sqlx errors convert into PKAvatarError::DatabaseError, which handlers map to a proper status code
+type Result<T> = std::result::Result<T, PKAvatarError>;
+
+#[derive(FromRow, Serialize)]
 pub struct ImageMeta {
     pub id: String,
     pub kind: ImageKind,
@@ -13,6 +21,7 @@ pub struct ImageMeta {
     pub file_size: i32,
     pub width: i32,
     pub height: i32,
+    #[serde(with = "time::serde::rfc3339::option")]
     pub uploaded_at: Option<OffsetDateTime>,
 
     pub original_url: Option<String>,
@@ -21,30 +30,157 @@ pub struct ImageMeta {
     pub original_type: Option<String>,
     pub uploaded_by_account: Option<i64>,
     pub uploaded_by_system: Option<Uuid>,
+    pub encode_quality: Option<f32>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_accessed_at: Option<OffsetDateTime>,
+    // what `id` was derived from
+    pub hash_algorithm: HashAlgorithm,
 }
 
+// everything we know about an image on the database side.
+// new per-image columns that don't belong in `ImageMeta` go here
 #[derive(FromRow, Serialize)]
+pub struct ExtendedImageMeta {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub meta: ImageMeta,
+
+    // id of the image that replaced this one (eg. after a retag)
+    pub deprecated_by: Option<String>,
+}
+
+#[derive(FromRow, Serialize, Clone, Default)]
 pub struct Stats {
     pub total_images: i64,
     pub total_file_size: i64,
 }
 
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineGranularity {
+    Hour,
+    Day,
+    Week,
+}
+
+impl TimelineGranularity {
+    fn date_trunc_field(&self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Week => "week",
+        }
+    }
+}
+
+#[derive(FromRow, Serialize)]
+pub struct TimelineBucket {
+    #[serde(with = "time::serde::rfc3339")]
+    pub bucket: OffsetDateTime,
+    pub count: i64,
+    pub total_size_bytes: i64,
+}
+
+#[derive(Serialize)]
+pub struct DimensionBuckets {
+    #[serde(rename = "<=128")]
+    pub le_128: i64,
+    #[serde(rename = "129-256")]
+    pub le_256: i64,
+    #[serde(rename = "257-512")]
+    pub le_512: i64,
+    #[serde(rename = "513-1024")]
+    pub le_1024: i64,
+    #[serde(rename = ">1024")]
+    pub gt_1024: i64,
+}
+
+#[derive(Serialize)]
+pub struct DimensionHistogram {
+    pub width_buckets: DimensionBuckets,
+    pub height_buckets: DimensionBuckets,
+}
+
+#[derive(FromRow)]
+pub struct AccountStats {
+    pub account_id: i64,
+    pub count: i64,
+    pub total_size: i64,
+}
+
+#[derive(FromRow, Serialize)]
+pub struct StaleImageStats {
+    pub count: i64,
+    pub total_size: i64,
+}
+
+// since startup, not persisted
+pub static QUEUE_DUPLICATES_SKIPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+pub struct RequeueResult {
+    pub requeued: u64,
+    pub skipped: u64,
+}
+
+#[derive(Serialize)]
+pub struct CompactResult {
+    pub deleted_already_stored: u64,
+    pub deleted_duplicate_urls: u64,
+    pub remaining: i64,
+}
+
 #[derive(FromRow)]
 pub struct ImageQueueEntry {
     pub itemid: i32,
     pub url: String,
     pub kind: ImageKind,
+    pub attachment_id: Option<i64>,
+    pub system_id: Option<Uuid>,
+    // transient failures so far, see `push_dead_letter`
+    pub attempt_count: i32,
+}
+
+#[derive(Deserialize)]
+pub struct NewQueueItem {
+    pub url: String,
+    pub kind: ImageKind,
+    pub system_id: Option<Uuid>,
 }
 
-pub async fn init(pool: &PgPool) -> anyhow::Result<()> {
+pub async fn init(pool: &PgPool, schema: Option<&str>) -> anyhow::Result<()> {
+    // only if configured, `create schema` needs the create privilege on the database even if it already exists
+    if let Some(schema) = schema {
+        pool.execute(format!("create schema if not exists {}", schema).as_str())
+            .await?;
+    }
     pool.execute(include_str!("./init.sql")).await?;
     Ok(())
 }
 
+// runs in the pool's after_connect hook, which wants a plain sqlx error
+pub async fn set_search_path(conn: &mut PgConnection, schema: &str) -> sqlx::Result<()> {
+    conn.execute(format!("set search_path to {}", schema).as_str())
+        .await?;
+    Ok(())
+}
+
+// unquoted postgres identifier, so no quoting/escaping is needed
+pub fn is_valid_schema_name(name: &str) -> bool {
+    name.len() <= 63
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+pub async fn check(pool: &PgPool) -> Result<()> {
+    sqlx::query("select 1").execute(pool).await?;
+    Ok(())
+}
+
 pub async fn get_by_original_url(
     pool: &PgPool,
     original_url: &str,
-) -> anyhow::Result<Option<ImageMeta>> {
+) -> Result<Option<ImageMeta>> {
     Ok(
         sqlx::query_as("select * from images where original_url = $1")
             .bind(original_url)
@@ -55,45 +191,254 @@ pub async fn get_by_original_url(
 pub async fn get_by_attachment_id(
     pool: &PgPool,
     attachment_id: u64,
-) -> anyhow::Result<Option<ImageMeta>> {
+) -> Result<Option<ImageMeta>> {
     Ok(
-        sqlx::query_as("select * from images where original_attachment_id = $1")
+        sqlx::query_as("select * from images where original_attachment_id = $1 and deprecated_by is null")
             .bind(attachment_id as i64)
             .fetch_optional(pool)
             .await?,
     )
 }
 
+pub async fn get_by_id(pool: &PgPool, id: &str) -> Result<Option<ImageMeta>> {
+    Ok(sqlx::query_as("select * from images where id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?)
+}
+
+pub async fn get_extended_metadata(
+    pool: &PgPool,
+    id: &str,
+) -> Result<Option<ExtendedImageMeta>> {
+    Ok(sqlx::query_as("select * from images where id = $1")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?)
+}
+
+#[derive(Serialize)]
+pub struct ImageVariant {
+    pub format: String,
+    pub url: String,
+    pub file_size: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+// every stored format of an image. there's only ever the one (webp/avif, or gif for animated banners) for now,
+// alternative formats get added here as they're stored
+pub async fn get_image_variants(pool: &PgPool, id: &str) -> Result<Vec<ImageVariant>> {
+    let Some(image) = get_extended_metadata(pool, id).await? else {
+        return Ok(vec![]);
+    };
+    let image = image.meta;
+
+    let format = ProcessedFormat::from_mime_type(&image.content_type)
+        .map_or(image.content_type.clone(), |x| x.extension().to_string());
+    Ok(vec![ImageVariant {
+        format,
+        url: image.url,
+        file_size: image.file_size,
+        width: image.width,
+        height: image.height,
+    }])
+}
+
+// suffix match on the original url (ignoring signed-url query params), so this is a full scan - fine for occasional debugging
+pub async fn get_images_by_filename(pool: &PgPool, filename: &str, limit: i64) -> Result<Vec<ImageMeta>> {
+    // discord filenames are full of underscores, which would otherwise match any character
+    let escaped = filename
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    Ok(
+        sqlx::query_as("select * from images where split_part(original_url, '?', 1) like '%/' || $1 order by uploaded_at desc limit $2")
+            .bind(escaped)
+            .bind(limit)
+            .fetch_all(pool)
+            .await?,
+    )
+}
+
+pub async fn get_images_larger_than(
+    pool: &PgPool,
+    min_size_bytes: i32,
+    kind: Option<ImageKind>,
+    limit: i64,
+) -> Result<Vec<ImageMeta>> {
+    Ok(sqlx::query_as(
+        "select * from images where file_size > $1 and ($2::text is null or kind = $2) order by file_size desc limit $3",
+    )
+    .bind(min_size_bytes)
+    .bind(kind)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?)
+}
+
+// really small outputs usually mean something went wrong (truncated upload, broken encode)
+pub async fn get_images_smaller_than(
+    pool: &PgPool,
+    max_size_bytes: i32,
+    kind: Option<ImageKind>,
+    limit: i64,
+) -> Result<Vec<ImageMeta>> {
+    Ok(sqlx::query_as(
+        "select * from images where file_size < $1 and ($2::text is null or kind = $2) order by file_size asc limit $3",
+    )
+    .bind(max_size_bytes)
+    .bind(kind)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?)
+}
+
+// attachments stored under more than one hash, eg. from a forced re-pull producing different output.
+// (the other direction can't happen, `id` is the primary key so a second attachment with identical output doesn't get a row)
+pub async fn find_attachment_duplicates(pool: &PgPool) -> Result<Vec<(i64, Vec<String>)>> {
+    Ok(sqlx::query_as(
+        "select original_attachment_id, array_agg(id order by uploaded_at) from images where original_attachment_id is not null group by original_attachment_id having count(*) > 1",
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_by_id_prefix(pool: &PgPool, prefix: &str) -> Result<Vec<ImageMeta>> {
+    Ok(
+        sqlx::query_as("select * from images where id like $1 || '%'")
+            .bind(prefix)
+            .fetch_all(pool)
+            .await?,
+    )
+}
+
 pub async fn pop_queue(
     pool: &PgPool,
-) -> anyhow::Result<Option<(Transaction<Postgres>, ImageQueueEntry)>> {
+) -> Result<Option<(Transaction<Postgres>, ImageQueueEntry)>> {
     let mut tx = pool.begin().await?;
-    let res: Option<ImageQueueEntry> = sqlx::query_as("delete from image_queue where itemid = (select itemid from image_queue order by itemid for update skip locked limit 1) returning *")
+    let res: Option<ImageQueueEntry> = sqlx::query_as("delete from image_queue where itemid = (select itemid from image_queue where next_attempt_at is null or next_attempt_at <= now() order by itemid for update skip locked limit 1) returning *")
         .fetch_optional(&mut *tx).await?;
     Ok(res.map(|x| (tx, x)))
 }
 
-pub async fn get_queue_length(pool: &PgPool) -> anyhow::Result<i64> {
+pub async fn get_queue_length(pool: &PgPool) -> Result<i64> {
     Ok(sqlx::query_scalar("select count(*) from image_queue")
         .fetch_one(pool)
         .await?)
 }
 
-pub async fn get_stats(pool: &PgPool) -> anyhow::Result<Stats> {
+pub async fn get_stats(pool: &PgPool) -> Result<Stats> {
     Ok(sqlx::query_as(
-        "select count(*) as total_images, sum(file_size) as total_file_size from images",
+        // sum() of no rows is null, not 0
+        "select count(*) as total_images, coalesce(sum(file_size), 0) as total_file_size from images",
     )
     .fetch_one(pool)
     .await?)
 }
 
-pub async fn add_image(pool: &PgPool, meta: ImageMeta) -> anyhow::Result<bool> {
+pub async fn get_stats_by_kind(pool: &PgPool) -> Result<HashMap<ImageKind, Stats>> {
+    let rows: Vec<(ImageKind, i64, i64)> = sqlx::query_as(
+        "select kind, count(*) as total_images, sum(file_size) as total_file_size from images group by kind",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut by_kind: HashMap<ImageKind, Stats> = rows
+        .into_iter()
+        .map(|(kind, total_images, total_file_size)| {
+            (kind, Stats { total_images, total_file_size })
+        })
+        .collect();
+
+    // kinds without any images don't show up in the group by at all
+    for kind in [ImageKind::Avatar, ImageKind::Banner] {
+        by_kind.entry(kind).or_default();
+    }
+    Ok(by_kind)
+}
+
+// (prefix, count, total bytes) by the first two characters of the id, which is also the storage "directory"
+pub async fn get_storage_prefix_distribution(pool: &PgPool) -> Result<Vec<(String, i64, i64)>> {
+    Ok(sqlx::query_as(
+        "select substring(id, 1, 2) as prefix, count(*), sum(file_size) from images group by prefix order by prefix",
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_upload_timeline(
+    pool: &PgPool,
+    granularity: TimelineGranularity,
+    days: u32,
+) -> Result<Vec<TimelineBucket>> {
+    Ok(sqlx::query_as(
+        "select date_trunc($1, uploaded_at) as bucket, count(*) as count, sum(file_size) as total_size_bytes from images where uploaded_at >= now() - make_interval(days => $2) group by bucket order by bucket",
+    )
+    .bind(granularity.date_trunc_field())
+    .bind(days as i32)
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn get_dimension_histogram(pool: &PgPool) -> Result<DimensionHistogram> {
+    fn buckets_for(column: &str) -> String {
+        format!(
+            "coalesce(sum(case when {0} <= 128 then 1 else 0 end), 0) as le_128, \
+             coalesce(sum(case when {0} > 128 and {0} <= 256 then 1 else 0 end), 0) as le_256, \
+             coalesce(sum(case when {0} > 256 and {0} <= 512 then 1 else 0 end), 0) as le_512, \
+             coalesce(sum(case when {0} > 512 and {0} <= 1024 then 1 else 0 end), 0) as le_1024, \
+             coalesce(sum(case when {0} > 1024 then 1 else 0 end), 0) as gt_1024",
+            column
+        )
+    }
+
+    // one scan for both; the row comes back as width buckets followed by height buckets
+    let row = sqlx::query(&format!(
+        "select {}, {} from images",
+        buckets_for("width"),
+        buckets_for("height")
+    ))
+    .fetch_one(pool)
+    .await?;
+
+    let get = |i: usize| -> Result<i64> { Ok(row.try_get::<i64, _>(i)?) };
+    Ok(DimensionHistogram {
+        width_buckets: DimensionBuckets {
+            le_128: get(0)?,
+            le_256: get(1)?,
+            le_512: get(2)?,
+            le_1024: get(3)?,
+            gt_1024: get(4)?,
+        },
+        height_buckets: DimensionBuckets {
+            le_128: get(5)?,
+            le_256: get(6)?,
+            le_512: get(7)?,
+            le_1024: get(8)?,
+            gt_1024: get(9)?,
+        },
+    })
+}
+
+// backed by images_uploaded_by_account_idx
+pub async fn get_top_uploaders(pool: &PgPool, limit: i64) -> Result<Vec<AccountStats>> {
+    Ok(sqlx::query_as(
+        "select uploaded_by_account as account_id, count(*) as count, sum(file_size) as total_size from images where uploaded_by_account is not null group by uploaded_by_account order by count desc limit $1",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await?)
+}
+
+// takes a connection as well, so it can be part of a bigger transaction (see `admin::retag_image`)
+pub async fn add_image<'c>(conn: impl Executor<'c, Database = Postgres>, meta: ImageMeta) -> Result<bool> {
     let kind_str = match meta.kind {
         ImageKind::Avatar => "avatar",
         ImageKind::Banner => "banner",
     };
 
-    let res = sqlx::query("insert into images (id, url, content_type, original_url, file_size, width, height, original_file_size, original_type, original_attachment_id, kind, uploaded_by_account, uploaded_by_system, uploaded_at) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, (now() at time zone 'utc')) on conflict (id) do nothing")
+    let res = sqlx::query("insert into images (id, url, content_type, original_url, file_size, width, height, original_file_size, original_type, original_attachment_id, kind, uploaded_by_account, uploaded_by_system, encode_quality, hash_algorithm, uploaded_at) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, (now() at time zone 'utc')) on conflict (id) do nothing")
         .bind(meta.id)
         .bind(meta.url)
         .bind(meta.content_type)
@@ -107,14 +452,256 @@ pub async fn add_image(pool: &PgPool, meta: ImageMeta) -> anyhow::Result<bool> {
         .bind(kind_str)
         .bind(meta.uploaded_by_account)
         .bind(meta.uploaded_by_system)
-        .execute(pool).await?;
+        .bind(meta.encode_quality)
+        .bind(meta.hash_algorithm)
+        .execute(conn).await?;
     Ok(res.rows_affected() > 0)
 }
 
-pub async fn push_queue(conn: &mut sqlx::PgConnection, url: &str, kind: ImageKind) -> anyhow::Result<()> {
-    sqlx::query("insert into image_queue (url, kind) values ($1, $2)")
+pub async fn mark_accessed(pool: &PgPool, ids: &[String]) -> Result<()> {
+    sqlx::query("update images set last_accessed_at = (now() at time zone 'utc') where id = any($1)")
+        .bind(ids)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// images that haven't been accessed (or uploaded, for ones from before access tracking) in `days`.
+// accesses are only seen when they go through us, not cdn hits,
+// so this is an upper bound on what's actually unused
+pub async fn get_stale_image_stats(pool: &PgPool, days: i32) -> Result<StaleImageStats> {
+    Ok(sqlx::query_as("select count(*) as count, coalesce(sum(file_size), 0)::bigint as total_size from images where coalesce(last_accessed_at, uploaded_at) < now() - make_interval(days => $1)")
+        .bind(days)
+        .fetch_one(pool)
+        .await?)
+}
+
+pub async fn deprecate_image(conn: &mut sqlx::PgConnection, id: &str, deprecated_by: &str) -> Result<()> {
+    sqlx::query("update images set deprecated_by = $2 where id = $1")
+        .bind(id)
+        .bind(deprecated_by)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// for when a retag comes out byte-for-byte the same, `add_image` leaves the existing row alone
+pub async fn set_kind(conn: &mut sqlx::PgConnection, id: &str, kind: ImageKind) -> Result<()> {
+    sqlx::query("update images set kind = $2 where id = $1")
+        .bind(id)
+        .bind(kind)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// counts against the item after a transient failure, and keeps it out of `pop_queue` for `delay`
+// so a short outage doesn't use up every attempt at once. the pop's transaction has to be rolled back first,
+// otherwise the row's still deleted
+pub async fn retry_later(pool: &PgPool, itemid: i32, delay: std::time::Duration) -> Result<()> {
+    sqlx::query("update image_queue set attempt_count = attempt_count + 1, next_attempt_at = now() + make_interval(secs => $2) where itemid = $1")
+        .bind(itemid)
+        .bind(delay.as_secs_f64())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// for items that kept failing with errors that would normally be retried. unlike `failed_migrations`,
+// these aren't known to be unmigratable, something might just have been down for a long time
+pub async fn push_dead_letter(
+    conn: &mut sqlx::PgConnection,
+    item: &ImageQueueEntry,
+    error_message: &str,
+) -> Result<()> {
+    sqlx::query("insert into image_queue_dead (itemid, url, kind, attachment_id, system_id, attempt_count, error_message) values ($1, $2, $3, $4, $5, $6, $7)")
+        .bind(item.itemid)
+        .bind(&item.url)
+        .bind(item.kind)
+        .bind(item.attachment_id)
+        .bind(item.system_id)
+        .bind(item.attempt_count + 1)
+        .bind(error_message)
+        .execute(conn).await?;
+    Ok(())
+}
+
+// back into the queue with a fresh set of attempts, eg. once whatever was down is back up
+pub async fn requeue_dead(pool: &PgPool, limit: i64) -> Result<RequeueResult> {
+    let mut tx = pool.begin().await?;
+    let items: Vec<(String, ImageKind, Option<i64>, Option<Uuid>)> = sqlx::query_as("delete from image_queue_dead where itemid in (select itemid from image_queue_dead order by itemid limit $1 for update skip locked) returning url, kind, attachment_id, system_id")
+        .bind(limit)
+        .fetch_all(&mut *tx).await?;
+    let mut res = RequeueResult::default();
+    for (url, kind, attachment_id, system_id) in items.iter() {
+        let inserted = sqlx::query("insert into image_queue (url, kind, attachment_id, system_id) values ($1, $2, $3, $4) on conflict (url, kind) do nothing")
+            .bind(url)
+            .bind(kind)
+            .bind(attachment_id)
+            .bind(system_id)
+            .execute(&mut *tx).await?
+            .rows_affected() > 0;
+        if inserted {
+            res.requeued += 1;
+        } else {
+            res.skipped += 1;
+        }
+    }
+    tx.commit().await?;
+    Ok(res)
+}
+
+pub async fn get_dead_letter_count(pool: &PgPool) -> Result<i64> {
+    Ok(sqlx::query_scalar("select count(*) from image_queue_dead")
+        .fetch_one(pool)
+        .await?)
+}
+
+pub async fn push_failed_migration(
+    conn: &mut sqlx::PgConnection,
+    url: &str,
+    kind: ImageKind,
+    error_code: &str,
+    error_message: &str,
+) -> Result<()> {
+    sqlx::query("insert into failed_migrations (url, kind, error_code, error_message) values ($1, $2, $3, $4)")
         .bind(url)
         .bind(kind)
+        .bind(error_code)
+        .bind(error_message)
         .execute(conn).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn requeue_failed(
+    pool: &PgPool,
+    error_code: Option<&str>,
+    limit: i64,
+) -> Result<RequeueResult> {
+    let mut tx = pool.begin().await?;
+    let items: Vec<(String, ImageKind)> = sqlx::query_as("delete from failed_migrations where id in (select id from failed_migrations where $1::text is null or error_code = $1 order by id limit $2 for update skip locked) returning url, kind")
+        .bind(error_code)
+        .bind(limit)
+        .fetch_all(&mut *tx).await?;
+    let mut res = RequeueResult::default();
+    for (url, kind) in items.iter() {
+        if push_queue(&mut tx, url, *kind).await? {
+            res.requeued += 1;
+        } else {
+            res.skipped += 1;
+        }
+    }
+    tx.commit().await?;
+    Ok(res)
+}
+
+// returns false if the url was already queued
+pub async fn push_queue(conn: &mut sqlx::PgConnection, url: &str, kind: ImageKind) -> Result<bool> {
+    // unparseable urls still get queued, the worker will skip them with a proper error
+    let attachment_id = parse_url(url).ok().map(|x| x.attachment_id as i64);
+    let res = sqlx::query("insert into image_queue (url, kind, attachment_id) values ($1, $2, $3) on conflict (url, kind) do nothing")
+        .bind(url)
+        .bind(kind)
+        .bind(attachment_id)
+        .execute(conn).await?;
+
+    let inserted = res.rows_affected() > 0;
+    if !inserted {
+        QUEUE_DUPLICATES_SKIPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(inserted)
+}
+
+// returns how many were actually inserted, the rest were already queued
+pub async fn push_queue_batch(conn: &mut sqlx::PgConnection, items: &[NewQueueItem]) -> Result<u64> {
+    if items.is_empty() {
+        return Ok(0);
+    }
+
+    let mut query = QueryBuilder::<Postgres>::new("insert into image_queue (url, kind, attachment_id, system_id) ");
+    query.push_values(items, |mut row, item| {
+        row.push_bind(&item.url)
+            .push_bind(item.kind)
+            .push_bind(parse_url(&item.url).ok().map(|x| x.attachment_id as i64))
+            .push_bind(item.system_id);
+    });
+    query.push(" on conflict (url, kind) do nothing");
+
+    let inserted = query.build().execute(conn).await?.rows_affected();
+    QUEUE_DUPLICATES_SKIPPED.fetch_add(items.len() as u64 - inserted, Ordering::Relaxed);
+    Ok(inserted)
+}
+
+// queue items whose attachment is already in `images`, ie. wasted worker cycles.
+// only covers items queued since `attachment_id` was added to the queue
+pub async fn get_orphan_queue_items(pool: &PgPool) -> Result<Vec<(i32, String)>> {
+    Ok(sqlx::query_as("select q.itemid, q.url from image_queue q inner join images i on i.original_attachment_id = q.attachment_id order by q.itemid")
+        .fetch_all(pool)
+        .await?)
+}
+
+// the queue is locked for the duration, workers just wait on their next pop until it's done
+pub async fn compact_queue(pool: &PgPool) -> Result<CompactResult> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("lock table image_queue in exclusive mode")
+        .execute(&mut *tx).await?;
+
+    let deleted_already_stored = sqlx::query("delete from image_queue q using images i where i.original_attachment_id = q.attachment_id")
+        .execute(&mut *tx).await?
+        .rows_affected();
+    // the unique index should prevent these nowadays, but older deployments can still have some
+    let deleted_duplicate_urls = sqlx::query("delete from image_queue a using image_queue b where a.url = b.url and a.kind = b.kind and a.itemid > b.itemid")
+        .execute(&mut *tx).await?
+        .rows_affected();
+    let remaining: i64 = sqlx::query_scalar("select count(*) from image_queue")
+        .fetch_one(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(CompactResult {
+        deleted_already_stored,
+        deleted_duplicate_urls,
+        remaining,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    #[test]
+    fn schema_names() {
+        for name in ["public", "avatars", "avatars_staging", "_tmp", "env2"] {
+            assert!(is_valid_schema_name(name), "{}", name);
+        }
+        for name in ["", "Avatars", "2fast", "avatars-staging", "a b", "x;drop table images", "\"quoted\""] {
+            assert!(!is_valid_schema_name(name), "{}", name);
+        }
+        assert!(is_valid_schema_name(&"a".repeat(63)));
+        assert!(!is_valid_schema_name(&"a".repeat(64)));
+    }
+
+    // same setup as `main`: search_path on every connection, then init
+    async fn schema_pool(options: PgPoolOptions, connect: PgConnectOptions, schema: &'static str) -> PgPool {
+        let pool = options
+            .after_connect(move |conn, _| Box::pin(async move { set_search_path(conn, schema).await }))
+            .connect_with(connect)
+            .await
+            .unwrap();
+        init(&pool, Some(schema)).await.unwrap();
+        pool
+    }
+
+    #[sqlx::test]
+    async fn schemas_dont_interfere(options: PgPoolOptions, connect: PgConnectOptions) {
+        let a = schema_pool(options.clone(), connect.clone(), "tenant_a").await;
+        let b = schema_pool(options, connect, "tenant_b").await;
+
+        let mut conn = a.acquire().await.unwrap();
+        push_queue(&mut conn, "https://cdn.discordapp.com/attachments/1/2/a.png", ImageKind::Avatar)
+            .await
+            .unwrap();
+        assert_eq!(get_queue_length(&a).await.unwrap(), 1);
+        assert_eq!(get_queue_length(&b).await.unwrap(), 0);
+    }
+}
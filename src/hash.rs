@@ -1,16 +1,50 @@
 use std::fmt::Display;
 
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 #[derive(Debug)]
 pub struct Hash([u8; 32]);
 
+// what image ids are derived from. the id itself doesn't say which one was used, the `images` row does.
+// switching is one-way in practice: the same bytes get a different id afterwards, so anything stored before
+// the switch isn't deduplicated against anything stored after it (it'll just be stored a second time)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case", type_name = "text")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    // a good bit faster, and this is only content addressing, not anything security-relevant
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
 impl Hash {
+    pub fn new(algorithm: HashAlgorithm, data: &[u8]) -> Hash {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hash::sha256(data),
+            HashAlgorithm::Blake3 => Hash::blake3(data),
+        }
+    }
+
     pub fn sha256(data: &[u8]) -> Hash {
         let mut hasher = Sha256::new();
         hasher.update(data);
         Hash(hasher.finalize().into())
     }
+
+    pub fn blake3(data: &[u8]) -> Hash {
+        Hash(blake3::hash(data).into())
+    }
 }
 
 impl Display for Hash {
@@ -0,0 +1,128 @@
+use crate::{ImageId, PKAvatarError, PullResponse};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+pub const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub const MAX_KEY_LENGTH: usize = 64;
+
+// responses to successful pulls, by the client-provided `Idempotency-Key`,
+// so retried requests get the original answer instead of doing the work again
+#[derive(Default)]
+pub struct IdempotencyCache {
+    entries: DashMap<String, KeyState>,
+}
+
+// `fingerprint` is a hash of the request the key was first used with,
+// reusing a key for a different request is an error rather than a replay
+enum KeyState {
+    // a request with this key is still running
+    InProgress {
+        fingerprint: u64,
+    },
+    Done {
+        stored_at: Instant,
+        fingerprint: u64,
+        id: ImageId,
+        response: PullResponse,
+    },
+}
+
+pub enum IdempotencyCheck<'a> {
+    // first time we see this key, it's held until the reservation is completed or dropped
+    New(Reservation<'a>),
+    // image id and response of the original request
+    Replay(ImageId, PullResponse),
+}
+
+impl IdempotencyCache {
+    pub fn check(&self, key: &str, fingerprint: u64) -> Result<IdempotencyCheck<'_>, PKAvatarError> {
+        match self.entries.entry(key.to_string()) {
+            Entry::Occupied(mut entry) => match entry.get() {
+                KeyState::Done {
+                    stored_at,
+                    fingerprint: stored,
+                    id,
+                    response,
+                } if stored_at.elapsed() < IDEMPOTENCY_KEY_TTL => {
+                    if *stored != fingerprint {
+                        return Err(PKAvatarError::IdempotencyKeyMismatch);
+                    }
+                    Ok(IdempotencyCheck::Replay(id.clone(), response.clone()))
+                }
+                KeyState::InProgress { fingerprint: stored } => {
+                    if *stored != fingerprint {
+                        return Err(PKAvatarError::IdempotencyKeyMismatch);
+                    }
+                    Err(PKAvatarError::IdempotencyKeyInProgress)
+                }
+                // expired, as good as a new key
+                KeyState::Done { .. } => {
+                    entry.insert(KeyState::InProgress { fingerprint });
+                    Ok(IdempotencyCheck::New(self.reservation(key)))
+                }
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(KeyState::InProgress { fingerprint });
+                Ok(IdempotencyCheck::New(self.reservation(key)))
+            }
+        }
+    }
+
+    fn reservation(&self, key: &str) -> Reservation<'_> {
+        Reservation {
+            cache: self,
+            key: key.to_string(),
+        }
+    }
+
+    pub fn evict_stale(&self) {
+        self.entries.retain(|_, state| match state {
+            KeyState::InProgress { .. } => true,
+            KeyState::Done { stored_at, .. } => stored_at.elapsed() < IDEMPOTENCY_KEY_TTL,
+        });
+    }
+}
+
+// dropping it without completing (the pull failed, or the request got cancelled) frees up the key again.
+// errors aren't stored, retrying those is the whole point
+pub struct Reservation<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+}
+
+impl Reservation<'_> {
+    pub fn complete(self, id: ImageId, response: PullResponse) {
+        if let Some(mut state) = self.cache.entries.get_mut(&self.key) {
+            if let KeyState::InProgress { fingerprint } = *state {
+                *state = KeyState::Done {
+                    stored_at: Instant::now(),
+                    fingerprint,
+                    id,
+                    response,
+                };
+            }
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.cache
+            .entries
+            .remove_if(&self.key, |_, state| matches!(state, KeyState::InProgress { .. }));
+    }
+}
+
+// uuids are the usual choice, but any short printable string works
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+pub async fn cleanup_task(cache: std::sync::Arc<IdempotencyCache>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        cache.evict_stale();
+    }
+}
@@ -0,0 +1,52 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// pulls currently running, by attachment id. a system with lots of members sharing one avatar
+// sends all of them at once, and only one of those needs to actually go to the cdn
+#[derive(Default)]
+pub struct InFlightPulls {
+    // gets the stored url once the pull is done. dropped without one if it failed
+    entries: DashMap<u64, Arc<watch::Sender<Option<String>>>>,
+}
+
+impl InFlightPulls {
+    // Ok if nothing else is pulling this attachment (and now this caller is),
+    // otherwise a receiver for the pull that's already running
+    pub fn start(&self, attachment_id: u64) -> Result<InFlightGuard<'_>, watch::Receiver<Option<String>>> {
+        match self.entries.entry(attachment_id) {
+            Entry::Occupied(entry) => Err(entry.get().subscribe()),
+            Entry::Vacant(entry) => {
+                let sender = Arc::new(watch::channel(None).0);
+                entry.insert(sender.clone());
+                Ok(InFlightGuard {
+                    pulls: self,
+                    attachment_id,
+                    sender,
+                })
+            }
+        }
+    }
+}
+
+// removes the entry when dropped, so errors (and cancelled requests) don't leave anyone waiting forever
+pub struct InFlightGuard<'a> {
+    pulls: &'a InFlightPulls,
+    attachment_id: u64,
+    sender: Arc<watch::Sender<Option<String>>>,
+}
+
+impl InFlightGuard<'_> {
+    pub fn finish(self, url: String) {
+        self.sender.send_replace(Some(url));
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.pulls
+            .entries
+            .remove_if(&self.attachment_id, |_, x| Arc::ptr_eq(x, &self.sender));
+    }
+}
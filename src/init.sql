@@ -21,4 +21,65 @@ create index if not exists images_uploaded_by_account_idx on images (uploaded_by
 create table if not exists image_queue (itemid serial primary key, url text not null, kind text not null);
 
 alter table images add column if not exists uploaded_by_system uuid;
-alter table images add column if not exists content_type text default 'image/webp';
\ No newline at end of file
+alter table images add column if not exists content_type text default 'image/webp';
+
+-- for prefix lookups (`like 'abc%'`)
+create index if not exists images_id_pattern_idx on images (id text_pattern_ops);
+
+-- items that can't be migrated, kept around so they can be requeued after a fix
+create table if not exists failed_migrations
+(
+    id            serial primary key,
+    url           text        not null,
+    kind          text        not null,
+    error_code    text        not null,
+    error_message text        not null,
+    failed_at     timestamptz not null default now()
+);
+
+create index if not exists failed_migrations_error_code_idx on failed_migrations (error_code);
+
+alter table image_queue add column if not exists attachment_id bigint;
+create index if not exists image_queue_attachment_id_idx on image_queue (attachment_id);
+
+alter table images add column if not exists deprecated_by text references images (id);
+
+-- older deployments can have duplicates, clear them out once before adding the constraint
+do
+$$
+    begin
+        if not exists (select 1 from pg_indexes where schemaname = current_schema() and indexname = 'image_queue_url_kind_idx') then
+            delete from image_queue a using image_queue b where a.url = b.url and a.kind = b.kind and a.itemid > b.itemid;
+            create unique index image_queue_url_kind_idx on image_queue (url, kind);
+        end if;
+    end
+$$;
+
+alter table image_queue add column if not exists system_id uuid;
+
+alter table images add column if not exists encode_quality real;
+
+create index if not exists images_file_size_idx on images (file_size);
+
+alter table images add column if not exists last_accessed_at timestamptz;
+
+alter table image_queue add column if not exists attempt_count int not null default 0;
+
+-- queue items that kept failing with transient errors, see `db::push_dead_letter`
+create table if not exists image_queue_dead
+(
+    itemid        int primary key,
+    url           text        not null,
+    kind          text        not null,
+    attachment_id bigint,
+    system_id     uuid,
+    attempt_count int         not null,
+    error_message text        not null,
+    failed_at     timestamptz not null default now()
+);
+
+-- what the id was derived from, everything before this column existed was sha256
+alter table images add column if not exists hash_algorithm text not null default 'sha256';
+
+-- set after a transient failure, the item isn't picked up again before then
+alter table image_queue add column if not exists next_attempt_at timestamptz;
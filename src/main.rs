@@ -1,30 +1,65 @@
+mod access_log;
+mod admin;
+mod attachment_cache;
+mod b2;
 mod db;
 mod hash;
+mod idempotency;
+mod in_flight;
 mod migrate;
 mod process;
 mod pull;
+mod pull_log;
+mod rate_limit;
+mod recent_errors;
 mod store;
+mod webp_anim;
 
+use std::collections::HashMap;
 use std::error::Error;
-use crate::db::{ImageMeta, Stats};
-use crate::pull::Puller;
-use crate::store::Storer;
-use axum::extract::State;
+use std::future::IntoFuture;
+use crate::db::{DimensionHistogram, ImageMeta, ImageVariant, Stats, TimelineBucket, TimelineGranularity};
+use crate::process::{OutputFormat, ProcessOptions, QuantizeDitherMode};
+use crate::pull::{ImagePuller, ParsedUrl, PullResult, Puller};
+use crate::access_log::AccessLog;
+use crate::attachment_cache::{AttachmentCache, CachedImage};
+use crate::hash::HashAlgorithm;
+use crate::idempotency::{IdempotencyCache, IdempotencyCheck};
+use crate::in_flight::{InFlightGuard, InFlightPulls};
+use crate::pull_log::RecentPullLog;
+use crate::rate_limit::RateLimiter;
+use crate::recent_errors::RecentErrors;
+use crate::store::{StorageBackend, Storer};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, State};
 use axum::routing::get;
+use axum::middleware::{self, Next};
 use axum::{
-    http::StatusCode,
+    http::{HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     routing::post,
-    Json, Router,
+    Extension, Json, Router,
 };
+use anyhow::Context;
+use futures::stream::{FuturesUnordered, StreamExt};
 use config::builder::DefaultState;
 use config::FileFormat;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use sqlx::postgres::PgPoolOptions;
 use thiserror::Error;
-use tracing::{error, info};
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, watch, RwLock, Semaphore};
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::decompression::RequestDecompressionLayer;
+use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 
 #[derive(Error, Debug)]
@@ -39,13 +74,25 @@ pub enum PKAvatarError {
     #[error("network error: {0}")]
     NetworkError(reqwest::Error),
 
+    #[error("timed out talking to discord cdn")]
+    NetworkTimeout,
+
     #[error("response is missing header: {0}")]
     MissingHeader(&'static str),
 
     #[error("unsupported content type: {0}")]
     UnsupportedContentType(String),
 
-    #[error("image file size too large ({0} > {1})")]
+    #[error("SVG images are not supported; please convert to PNG or WebP first")]
+    SvgNotSupported,
+
+    #[error("no Content-Type header, and the image type couldn't be detected from its contents")]
+    CannotDetectContentType,
+
+    #[error("Lottie animation format is not supported")]
+    LottieNotSupported,
+
+    #[error("image file size too large ({0} bytes, the limit is {1})")]
     ImageFileSizeTooLarge(u64, u64),
 
     #[error("unsupported image format: {0:?}")]
@@ -54,17 +101,104 @@ pub enum PKAvatarError {
     #[error("could not detect image format")]
     UnknownImageFormat,
 
-    #[error("original image dimensions too large: {0:?} > {1:?}")]
+    #[error("original image dimensions too large: {0:?}, the limit is {1:?}")]
     ImageDimensionsTooLarge((u32, u32), (u32, u32)),
 
+    #[error("image has zero width or height: {0:?}")]
+    ImageDimensionsZero((u32, u32)),
+
+    #[error("landscape avatars are not allowed: {0}x{1}")]
+    LandscapeAvatarRejected(u32, u32),
+
+    #[error("could not convert image color space: {0}")]
+    ColorSpaceConversionFailed(String),
+
     #[error("could not decode image, is it corrupted?")]
     ImageFormatError(#[from] image::ImageError),
 
+    #[error("hash prefix must be at least {1} alphanumeric characters (got {0:?})")]
+    InvalidHashPrefix(String, usize),
+
+    #[error("invalid attachment id: {0:?}")]
+    InvalidAttachmentId(String),
+
+    #[error("image not found")]
+    ImageNotFound,
+
+    #[error("upload not found (finished uploads aren't tracked)")]
+    UploadNotFound,
+
+    #[error("invalid upload: {0}")]
+    InvalidUpload(String),
+
+    #[error("too many items ({0} > {1})")]
+    TooManyItems(usize, usize),
+
+    #[error("too many animation frames ({0} > {1})")]
+    TooManyFrames(usize, usize),
+
+    #[error("Idempotency-Key must be 1-64 printable ascii characters")]
+    InvalidIdempotencyKey,
+
+    #[error("this Idempotency-Key was already used for a different request")]
+    IdempotencyKeyMismatch,
+
+    #[error("a request with this Idempotency-Key is still in progress")]
+    IdempotencyKeyInProgress,
+
+    #[error("missing or invalid authorization token")]
+    Unauthorized,
+
+    #[error("rate limited ({0})")]
+    RateLimited(&'static str),
+
+    #[error("database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
     #[error("unknown error")]
     InternalError(#[from] anyhow::Error),
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, sqlx::Type, PartialEq)]
+impl PKAvatarError {
+    // stable machine-readable identifier, for storing/filtering without matching on messages
+    pub fn code(&self) -> &'static str {
+        match self {
+            PKAvatarError::InvalidCdnUrl => "invalid_cdn_url",
+            PKAvatarError::BadCdnResponse(_) => "bad_cdn_response",
+            PKAvatarError::NetworkError(_) => "network_error",
+            PKAvatarError::NetworkTimeout => "network_timeout",
+            PKAvatarError::MissingHeader(_) => "missing_header",
+            PKAvatarError::UnsupportedContentType(_) => "unsupported_content_type",
+            PKAvatarError::SvgNotSupported => "svg_not_supported",
+            PKAvatarError::CannotDetectContentType => "cannot_detect_content_type",
+            PKAvatarError::LottieNotSupported => "lottie_not_supported",
+            PKAvatarError::ImageFileSizeTooLarge(_, _) => "file_size_too_large",
+            PKAvatarError::UnsupportedImageFormat(_) => "unsupported_format",
+            PKAvatarError::UnknownImageFormat => "unknown_format",
+            PKAvatarError::ImageDimensionsTooLarge(_, _) => "dimensions_too_large",
+            PKAvatarError::ImageDimensionsZero(_) => "dimensions_zero",
+            PKAvatarError::LandscapeAvatarRejected(_, _) => "landscape_avatar_rejected",
+            PKAvatarError::ColorSpaceConversionFailed(_) => "color_space_conversion_failed",
+            PKAvatarError::ImageFormatError(_) => "image_format_error",
+            PKAvatarError::InvalidHashPrefix(_, _) => "invalid_hash_prefix",
+            PKAvatarError::ImageNotFound => "image_not_found",
+            PKAvatarError::InvalidAttachmentId(_) => "invalid_attachment_id",
+            PKAvatarError::UploadNotFound => "upload_not_found",
+            PKAvatarError::InvalidUpload(_) => "invalid_upload",
+            PKAvatarError::TooManyItems(_, _) => "too_many_items",
+            PKAvatarError::TooManyFrames(_, _) => "too_many_frames",
+            PKAvatarError::InvalidIdempotencyKey => "invalid_idempotency_key",
+            PKAvatarError::IdempotencyKeyMismatch => "idempotency_key_mismatch",
+            PKAvatarError::IdempotencyKeyInProgress => "idempotency_key_in_progress",
+            PKAvatarError::Unauthorized => "unauthorized",
+            PKAvatarError::RateLimited(_) => "rate_limited",
+            PKAvatarError::DatabaseError(_) => "database_error",
+            PKAvatarError::InternalError(_) => "internal_error",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, sqlx::Type, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 #[sqlx(rename_all = "snake_case", type_name = "text")]
 pub enum ImageKind {
@@ -79,8 +213,16 @@ impl ImageKind {
             Self::Banner => (1024, 1024),
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Avatar => "avatar",
+            Self::Banner => "banner",
+        }
+    }
 }
-#[derive(Deserialize, Debug)]
+// `Hash` is what ties an Idempotency-Key to the request it was first used with
+#[derive(Deserialize, Debug, Hash)]
 pub struct PullRequest {
     url: String,
     kind: ImageKind,
@@ -89,192 +231,2439 @@ pub struct PullRequest {
 
     #[serde(default)]
     force: bool,
+
+    // only honored if `debug_mode_enabled` is set in the config
+    #[serde(default)]
+    include_timing: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct PullResponse {
     url: String,
     new: bool,
+    // when this request pulled the image, null if an existing image was returned
+    #[serde(with = "time::serde::rfc3339::option")]
+    pulled_at: Option<OffsetDateTime>,
+    // of the stored image
+    content_type: String,
+    width: u32,
+    height: u32,
+    file_size: u32,
+    // null for images stored before this was tracked
+    original_file_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timing: Option<PullTiming>,
 }
 
-async fn pull(
-    State(state): State<AppState>,
-    Json(req): Json<PullRequest>,
-) -> Result<Json<PullResponse>, PKAvatarError> {
+#[derive(Serialize, Clone, Copy)]
+pub struct PullTiming {
+    pull_ms: u64,
+    process_ms: u64,
+    // includes writing the database row
+    store_ms: u64,
+    total_ms: u64,
+}
+
+// the error response is built inside the span, so anything logged in
+// `PKAvatarError::into_response` carries these fields too
+#[instrument(skip_all, fields(attachment_id = tracing::field::Empty, kind = ?req.kind, system_id = ?req.system_id))]
+async fn pull(State(state): State<AppState>, headers: HeaderMap, Json(req): Json<PullRequest>) -> Response {
+    let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        None => None,
+        Some(value) => match value.to_str().ok().filter(|x| idempotency::is_valid_key(x)) {
+            Some(key) => Some(key.to_string()),
+            None => return PKAvatarError::InvalidIdempotencyKey.into_response(),
+        },
+    };
+
+    // only the short form gets kept, see `ParsedUrl`'s Display
+    let display_url = pull::parse_url(&req.url).ok().map(|x| x.to_string());
+    let kind = req.kind;
+
+    let res = match &idempotency_key {
+        Some(key) => match state.idempotency.check(key, request_fingerprint(&req)) {
+            Ok(IdempotencyCheck::Replay(id, mut cached)) => {
+                // whoever sent the first request already got to see it was new
+                cached.new = false;
+                cached.timing = None;
+                Ok((Extension(id), Json(cached)))
+            }
+            Ok(IdempotencyCheck::New(reservation)) => {
+                let res = pull_inner(state.clone(), req).await;
+                if let Ok((Extension(id), Json(response))) = &res {
+                    reservation.complete(id.clone(), response.clone());
+                }
+                res
+            }
+            Err(e) => Err(e),
+        },
+        None => pull_inner(state.clone(), req).await,
+    };
+
+    let status = if res.is_ok() { "success" } else { "error" };
+    metrics::counter!("pkavatars_pulls_total", "status" => status, "kind" => kind.as_str()).increment(1);
+
+    // client errors (bad urls, unsupported formats) are the caller's problem and would drown out everything else
+    if let Err(e) = &res {
+        if e.status_code().is_server_error() {
+            state.recent_errors.record(e, display_url, Some(kind));
+        }
+    }
+
+    let mut res = res.into_response();
+    if let Some(key) = idempotency_key.and_then(|x| HeaderValue::from_str(&x).ok()) {
+        res.headers_mut().insert(IDEMPOTENCY_KEY_HEADER, key);
+    }
+    res
+}
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+// only compared within this process (the cache isn't persisted), so the std hasher is fine
+fn request_fingerprint(req: &PullRequest) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    req.hash(&mut hasher);
+    hasher.finish()
+}
+
+async fn pull_inner(
+    state: AppState,
+    req: PullRequest,
+) -> Result<(Extension<ImageId>, Json<PullResponse>), PKAvatarError> {
+    match start_pull(&state, req).await? {
+        PullStart::Existing(res) => Ok(res),
+        PullStart::Pulled(pending) => finish_pull(&state, pending).await,
+    }
+}
+
+enum PullStart<'a> {
+    // already stored, nothing left to do
+    Existing((Extension<ImageId>, Json<PullResponse>)),
+    Pulled(PendingPull<'a>),
+}
+
+// downloaded but not processed yet, see `finish_pull`
+struct PendingPull<'a> {
+    parsed: ParsedUrl,
+    kind: ImageKind,
+    uploaded_by: Option<u64>,
+    system_id: Option<Uuid>,
+    result: PullResult,
+    in_flight: Option<InFlightGuard<'a>>,
+    pulled_at: OffsetDateTime,
+    include_timing: bool,
+    time_before: tokio::time::Instant,
+    pull_time: Duration,
+}
+
+// the network half of a pull: checks for an existing image, then downloads it.
+// split from `finish_pull` so /pull/batch can download several at once but process them one by one
+async fn start_pull(state: &AppState, req: PullRequest) -> Result<PullStart<'_>, PKAvatarError> {
+    let time_before = tokio::time::Instant::now();
+    // timings tell callers things like whether an image was cached, so they're opt-in on the server side too
+    let include_timing = req.include_timing && state.config.debug_mode_enabled;
+
     let parsed = pull::parse_url(&req.url) // parsing beforehand to "normalize"
         .map_err(|_| PKAvatarError::InvalidCdnUrl)?;
+    tracing::Span::current().record("attachment_id", parsed.attachment_id);
 
-    if !req.force {
+    if let Some(count) = state.recent_pulls.record(parsed.attachment_id) {
+        warn!(attachment_id = parsed.attachment_id, count, "duplicate pull within 60s");
+    }
+
+    let existing_response = |image: CachedImage| {
+        state.access_log.record(&image.id);
+        let timing = include_timing.then(|| PullTiming {
+            pull_ms: 0,
+            process_ms: 0,
+            store_ms: 0,
+            total_ms: time_before.elapsed().as_millis() as u64,
+        });
+        PullStart::Existing(image_response(image, false, None, timing))
+    };
+
+    let mut in_flight = None;
+    if req.force {
+        state.attachment_cache.remove(parsed.attachment_id);
+    } else {
+        if let Some(cached) = state.attachment_cache.get(parsed.attachment_id) {
+            return Ok(existing_response(cached));
+        }
         if let Some(existing) = db::get_by_attachment_id(&state.pool, parsed.attachment_id).await? {
-            return Ok(Json(PullResponse {
-                url: existing.url,
-                new: false,
-            }));
+            let cached = CachedImage::from(&existing);
+            state.attachment_cache.insert(parsed.attachment_id, cached.clone());
+            return Ok(existing_response(cached));
         }
+
+        // if someone else is already pulling this, wait for them instead of doing it all again
+        in_flight = loop {
+            match state.in_flight.start(parsed.attachment_id) {
+                Ok(guard) => break Some(guard),
+                Err(mut rx) => {
+                    // errors if the other pull failed, then this one gets a go at it
+                    if rx.wait_for(|x| x.is_some()).await.is_err() {
+                        continue;
+                    }
+                    match db::get_by_attachment_id(&state.pool, parsed.attachment_id).await? {
+                        Some(existing) => return Ok(existing_response(CachedImage::from(&existing))),
+                        None => break None,
+                    }
+                }
+            }
+        };
+    }
+
+    let pulled_at = OffsetDateTime::now_utc();
+    let time_before_pull = tokio::time::Instant::now();
+    let result = state.puller.pull(&parsed, req.kind).await?;
+
+    Ok(PullStart::Pulled(PendingPull {
+        parsed,
+        kind: req.kind,
+        uploaded_by: req.uploaded_by,
+        system_id: req.system_id,
+        result,
+        in_flight,
+        pulled_at,
+        include_timing,
+        time_before,
+        pull_time: time_before_pull.elapsed(),
+    }))
+}
+
+// the cpu half of a pull: process, store, and let anyone waiting on the same attachment know
+async fn finish_pull(
+    state: &AppState,
+    pending: PendingPull<'_>,
+) -> Result<(Extension<ImageId>, Json<PullResponse>), PKAvatarError> {
+    let attachment_id = pending.parsed.attachment_id;
+    let stored = process_and_store(
+        state,
+        NewImage {
+            data: pending.result.data,
+            kind: pending.kind,
+            original_url: Some(pending.parsed.full_url),
+            original_type: pending.result.content_type,
+            original_attachment_id: Some(attachment_id),
+            uploaded_by: pending.uploaded_by,
+            system_id: pending.system_id,
+        },
+    )
+    .await?;
+
+    state.attachment_cache.insert(attachment_id, stored.image.clone());
+    if let Some(in_flight) = pending.in_flight {
+        in_flight.finish(stored.image.url.clone());
     }
 
-    let result = state.puller.pull(&parsed).await?;
+    let timing = pending.include_timing.then(|| PullTiming {
+        pull_ms: pending.pull_time.as_millis() as u64,
+        process_ms: stored.process_time.as_millis() as u64,
+        store_ms: stored.store_time.as_millis() as u64,
+        total_ms: pending.time_before.elapsed().as_millis() as u64,
+    });
+    Ok(image_response(stored.image, stored.is_new, Some(pending.pulled_at), timing))
+}
+
+fn image_response(
+    image: CachedImage,
+    new: bool,
+    pulled_at: Option<OffsetDateTime>,
+    timing: Option<PullTiming>,
+) -> (Extension<ImageId>, Json<PullResponse>) {
+    (
+        Extension(ImageId(image.id, image.hash_algorithm)),
+        Json(PullResponse {
+            url: image.url,
+            new,
+            pulled_at,
+            content_type: image.content_type,
+            width: image.width,
+            height: image.height,
+            file_size: image.file_size,
+            original_file_size: image.original_file_size,
+            timing,
+        }),
+    )
+}
+
+// an image that's been fetched (or uploaded) but not processed yet
+struct NewImage {
+    data: Vec<u8>,
+    kind: ImageKind,
+    original_url: Option<String>,
+    original_type: String,
+    original_attachment_id: Option<u64>,
+    uploaded_by: Option<u64>,
+    system_id: Option<Uuid>,
+}
+
+struct StoredImage {
+    image: CachedImage,
+    // false if the processed output was already stored (same bytes from a different source)
+    is_new: bool,
+    process_time: Duration,
+    // includes writing the database row
+    store_time: Duration,
+}
 
-    let original_file_size = result.data.len();
-    let encoded = process::process_async(result.data, req.kind).await?;
+// process -> store -> database row, the part /pull and /upload have in common.
+// caching, in-flight tracking and such are up to the caller
+async fn process_and_store(state: &AppState, image: NewImage) -> Result<StoredImage, PKAvatarError> {
+    let time_before = tokio::time::Instant::now();
+    let original_file_size = image.data.len();
+    let encoded = process::process_async(image.data, image.kind, ProcessOptions::new(&state.config)).await?;
+    let time_after_process = tokio::time::Instant::now();
+    metrics::histogram!("pkavatars_image_bytes_original").record(original_file_size as f64);
+    metrics::histogram!("pkavatars_image_bytes_stored").record(encoded.data.len() as f64);
 
     let store_res = state.storer.store(&encoded).await?;
-    let final_url = format!("{}{}", state.config.base_url, store_res.path);
+    let final_url = state.url_for_path(&store_res.id, &store_res.path);
     let is_new = db::add_image(
         &state.pool,
         ImageMeta {
-            id: store_res.id,
+            id: store_res.id.clone(),
             url: final_url.clone(),
             content_type: encoded.format.mime_type().to_string(),
-            original_url: Some(parsed.full_url),
-            original_type: Some(result.content_type),
+            original_url: image.original_url,
+            original_type: Some(image.original_type),
             original_file_size: Some(original_file_size as i32),
-            original_attachment_id: Some(parsed.attachment_id as i64),
+            original_attachment_id: image.original_attachment_id.map(|x| x as i64),
             file_size: encoded.data.len() as i32,
             width: encoded.width as i32,
             height: encoded.height as i32,
-            kind: req.kind,
+            kind: image.kind,
             uploaded_at: None,
-            uploaded_by_account: req.uploaded_by.map(|x| x as i64),
-            uploaded_by_system: req.system_id,
+            uploaded_by_account: image.uploaded_by.map(|x| x as i64),
+            uploaded_by_system: image.system_id,
+            encode_quality: encoded.quality_used,
+            last_accessed_at: None,
+            hash_algorithm: encoded.hash_algorithm,
         },
     )
     .await?;
+    let time_after = tokio::time::Instant::now();
 
-    Ok(Json(PullResponse {
-        url: final_url,
-        new: is_new,
-    }))
+    Ok(StoredImage {
+        image: CachedImage {
+            id: store_res.id,
+            url: final_url,
+            content_type: encoded.format.mime_type().to_string(),
+            width: encoded.width,
+            height: encoded.height,
+            file_size: encoded.data.len() as u32,
+            original_file_size: Some(original_file_size as u32),
+            hash_algorithm: encoded.hash_algorithm,
+        },
+        is_new,
+        process_time: time_after_process - time_before,
+        store_time: time_after - time_after_process,
+    })
 }
 
-pub async fn stats(State(state): State<AppState>) -> Result<Json<Stats>, PKAvatarError> {
-    Ok(Json(db::get_stats(&state.pool).await?))
+const MAX_BATCH_SIZE: usize = 50;
+const DEFAULT_BATCH_DOWNLOAD_CONCURRENCY: usize = 8;
+// processing is what actually saturates the blocking pool, so one at a time unless configured otherwise
+const DEFAULT_BATCH_PROCESS_CONCURRENCY: usize = 1;
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BatchPullItem {
+    Ok(PullResponse),
+    Err(ErrorResponse),
 }
 
-fn load_config() -> anyhow::Result<Config> {
-    config::ConfigBuilder::<DefaultState>::default()
-        .add_source(config::File::new("config", FileFormat::Toml).required(false))
-        .add_source(
-            config::Environment::with_prefix("PK_AVATAR")
-                .prefix_separator("__")
-                .separator("__"),
-        )
-        .build()?
-        .try_deserialize::<Config>()
-        .map_err(Into::into)
+// always 200, the results are in the same order as the request and each one has either succeeded or not.
+// downloads run concurrently and hand what they pulled over a channel to a single consumer,
+// which processes and stores them (up to `batch_process_concurrency` at a time) as they come in
+async fn pull_batch(
+    State(state): State<AppState>,
+    Json(reqs): Json<Vec<PullRequest>>,
+) -> Result<Json<Vec<BatchPullItem>>, PKAvatarError> {
+    if reqs.len() > MAX_BATCH_SIZE {
+        return Err(PKAvatarError::TooManyItems(reqs.len(), MAX_BATCH_SIZE));
+    }
+    metrics::histogram!("pkavatars_batch_pull_size").record(reqs.len() as f64);
+
+    // for metrics and recent errors once everything's done, `reqs` is consumed by then
+    let items = reqs
+        .iter()
+        .map(|req| (pull::parse_url(&req.url).ok().map(|x| x.to_string()), req.kind))
+        .collect::<Vec<_>>();
+
+    let download_semaphore = Semaphore::new(
+        state
+            .config
+            .batch_download_concurrency
+            .unwrap_or(DEFAULT_BATCH_DOWNLOAD_CONCURRENCY)
+            .max(1),
+    );
+    let process_concurrency = state
+        .config
+        .batch_process_concurrency
+        .unwrap_or(DEFAULT_BATCH_PROCESS_CONCURRENCY)
+        .max(1);
+
+    // big enough for every item, downloads never wait on the consumer
+    let (tx, rx) = mpsc::channel(reqs.len().max(1));
+    let downloads = futures::future::join_all(reqs.into_iter().enumerate().map(|(i, req)| {
+        let state = &state;
+        let download_semaphore = &download_semaphore;
+        let tx = tx.clone();
+        async move {
+            let _permit = download_semaphore
+                .acquire()
+                .await
+                .map_err(|e| PKAvatarError::InternalError(e.into()))?;
+            match start_pull(state, req).await? {
+                PullStart::Existing(res) => Ok(Some(res)),
+                PullStart::Pulled(pending) => {
+                    // can't fail, the receiver outlives every sender
+                    let _ = tx.send((i, pending)).await;
+                    Ok(None)
+                }
+            }
+        }
+    }));
+    // otherwise the consumer would never see the channel close
+    drop(tx);
+
+    let processing = async {
+        let mut rx = rx;
+        let mut running = FuturesUnordered::new();
+        let mut processed = Vec::new();
+        loop {
+            tokio::select! {
+                Some((i, pending)) = rx.recv(), if running.len() < process_concurrency => {
+                    let state = &state;
+                    running.push(async move { (i, finish_pull(state, pending).await) });
+                }
+                Some(done) = running.next() => processed.push(done),
+                // channel closed and everything's been processed
+                else => break,
+            }
+        }
+        processed
+    };
+
+    let (mut results, processed) = tokio::join!(downloads, processing);
+    for (i, res) in processed {
+        results[i] = res.map(Some);
+    }
+
+    Ok(Json(
+        results
+            .into_iter()
+            .zip(items)
+            .map(|(res, (display_url, kind))| {
+                // every `None` was sent to the consumer and replaced above
+                let res = res.map(|x| x.expect("batch item was never processed"));
+
+                let status = if res.is_ok() { "success" } else { "error" };
+                metrics::counter!("pkavatars_pulls_total", "status" => status, "kind" => kind.as_str()).increment(1);
+                match res {
+                    Ok((_, Json(response))) => BatchPullItem::Ok(response),
+                    Err(e) => {
+                        if e.status_code().is_server_error() {
+                            state.recent_errors.record(&e, display_url, Some(kind));
+                        }
+                        warn!("error in batch pull: {}", e.source().unwrap_or(&e));
+                        BatchPullItem::Err(ErrorResponse { error: e.to_string() })
+                    }
+                }
+            })
+            .collect(),
+    ))
 }
 
-#[derive(Clone)]
-pub struct AppState {
-    storer: Arc<Storer>,
-    puller: Arc<Puller>,
-    pool: PgPool,
-    config: Arc<Config>,
+// same as /pull, except the image comes straight from the client instead of the discord cdn.
+// form fields: `file`, `kind`, and optionally `uploaded_by` and `system_id`
+async fn upload(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<(Extension<ImageId>, Json<PullResponse>), PKAvatarError> {
+    let gif_max_size = state.config.gif_max_size_bytes.unwrap_or(pull::DEFAULT_GIF_MAX_SIZE);
+    let max_any_kind = max_upload_size(&state.config);
+    let mut file = None;
+    let mut kind = None;
+    let mut uploaded_by = None;
+    let mut system_id = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| PKAvatarError::InvalidUpload(e.body_text()))?
+    {
+        match field.name() {
+            Some("file") => {
+                let mime = pull::supported_mime(
+                    field.content_type().map(|x| x.split(';').next().unwrap_or("")).unwrap_or(""),
+                )?;
+                // the form's field order isn't fixed, so `kind` might not be known yet
+                let max_size = match kind {
+                    Some(kind) => pull::max_size_for(mime, gif_max_size, state.config.limits_for_kind(kind).max_bytes),
+                    None => pull::max_size_for(mime, gif_max_size, max_any_kind),
+                };
+                // parts don't usually come with their own length, the check once the form's done covers those
+                let size = field
+                    .headers()
+                    .get(axum::http::header::CONTENT_LENGTH)
+                    .and_then(|x| x.to_str().ok())
+                    .and_then(|x| x.parse::<u64>().ok());
+                if let Some(size) = size.filter(|x| *x > max_size) {
+                    return Err(PKAvatarError::ImageFileSizeTooLarge(size, max_size));
+                }
+                let data = field
+                    .bytes()
+                    .await
+                    .map_err(|e| PKAvatarError::InvalidUpload(e.body_text()))?;
+                file = Some((data.to_vec(), mime));
+            }
+            Some(name @ ("kind" | "uploaded_by" | "system_id")) => {
+                let name = name.to_string();
+                let value = field
+                    .text()
+                    .await
+                    .map_err(|e| PKAvatarError::InvalidUpload(e.body_text()))?;
+                let invalid = || PKAvatarError::InvalidUpload(format!("invalid {}: {:?}", name, value));
+                match name.as_str() {
+                    "kind" => {
+                        kind = Some(match value.as_str() {
+                            "avatar" => ImageKind::Avatar,
+                            "banner" => ImageKind::Banner,
+                            _ => return Err(invalid()),
+                        })
+                    }
+                    "uploaded_by" => uploaded_by = Some(value.parse::<u64>().map_err(|_| invalid())?),
+                    _ => system_id = Some(value.parse::<Uuid>().map_err(|_| invalid())?),
+                }
+            }
+            // unknown fields are ignored, same as unknown json keys on /pull
+            _ => {}
+        }
+    }
+
+    let (data, content_type) = file.ok_or_else(|| PKAvatarError::InvalidUpload("missing file".to_string()))?;
+    let kind = kind.ok_or_else(|| PKAvatarError::InvalidUpload("missing kind".to_string()))?;
+    let max_size = pull::max_size_for(content_type, gif_max_size, state.config.limits_for_kind(kind).max_bytes);
+    if data.len() as u64 > max_size {
+        return Err(PKAvatarError::ImageFileSizeTooLarge(data.len() as u64, max_size));
+    }
+
+    let pulled_at = OffsetDateTime::now_utc();
+    let stored = process_and_store(
+        &state,
+        NewImage {
+            data,
+            kind,
+            original_url: None,
+            original_type: content_type.to_string(),
+            original_attachment_id: None,
+            uploaded_by,
+            system_id,
+        },
+    )
+    .await?;
+
+    info!(
+        "uploaded {} ({}k -> {}k)",
+        stored.image.url,
+        stored.image.original_file_size.unwrap_or_default() / 1024,
+        stored.image.file_size / 1024
+    );
+    Ok(image_response(stored.image, stored.is_new, Some(pulled_at), None))
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+// the largest file any kind of upload is allowed to be
+fn max_upload_size(config: &Config) -> u64 {
+    config
+        .limits_for_kind(ImageKind::Avatar)
+        .max_bytes
+        .max(config.limits_for_kind(ImageKind::Banner).max_bytes)
+        .max(config.gif_max_size_bytes.unwrap_or(pull::DEFAULT_GIF_MAX_SIZE))
+}
 
-    let config = load_config()?;
+// room for the multipart boundaries and the other form fields on top of the file itself
+const UPLOAD_FORM_OVERHEAD: u64 = 64 * 1024;
 
-    let storer = Arc::new(Storer::new(&config)?);
-    let puller = Arc::new(Puller::new()?);
+// set as a response extension by handlers that return a single image, along with what the id was derived from
+#[derive(Clone)]
+pub struct ImageId(String, HashAlgorithm);
 
-    info!("connecting to database...");
-    let pool = PgPoolOptions::new().max_connections(config.db_connections.unwrap_or(5)).connect(&config.db).await?;
-    db::init(&pool).await?;
+// lets clients check what they downloaded against what we stored.
+// the value is the image id, i.e. the first 128 bits of the hash of the stored bytes in lowercase base32
+// (see `hash::Hash`), not a full digest - hence the `-trunc128` label instead of a plain `sha256:`.
+// the algorithm comes from the image's row, so it's still right for images stored before `hash_algorithm` was changed
+async fn content_hash_header(req: axum::extract::Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    if let Some(ImageId(id, algorithm)) = res.extensions().get::<ImageId>().cloned() {
+        if let Ok(value) = HeaderValue::from_str(&format!("{}-trunc128:{}", algorithm.as_str(), id)) {
+            res.headers_mut().insert("x-content-hash", value);
+        }
+    }
+    res
+}
 
-    let state = AppState {
-        storer,
-        puller,
-        pool,
-        config: Arc::new(config),
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonNaming {
+    #[default]
+    SnakeCase,
+    CamelCase,
+}
+
+// everything serializes as snake_case, this rewrites the keys of json responses on the way out.
+// only struct fields get renamed, the keys of maps that hold data (see `DATA_MAP_FIELDS`) are left alone
+async fn camel_case_json(req: axum::extract::Request, next: Next) -> Response {
+    let top_level_is_data_map = DATA_MAP_ROUTES.contains(&req.uri().path());
+    let res = next.run(req).await;
+    let is_json = res
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .is_some_and(|x| x.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return res;
+    }
+
+    // responses are built in memory anyway, so this is only a guard against buffering something unexpectedly huge
+    let size = axum::body::HttpBody::size_hint(res.body()).upper();
+    if !size.is_some_and(|x| x <= MAX_CAMEL_CASE_BODY_SIZE as u64) {
+        warn!("not rewriting json response of unknown or too large size ({:?} bytes)", size);
+        return res;
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_CAMEL_CASE_BODY_SIZE).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("error reading response body: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
     };
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(value) => serde_json::to_vec(&camel_case_keys(value, !top_level_is_data_map))
+            .expect("json value serialization is infallible"),
+        Err(_) => bytes.to_vec(),
+    };
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    Response::from_parts(parts, axum::body::Body::from(body))
+}
 
-    migrate::spawn_migrate_workers(Arc::new(state.clone()), state.config.migrate_worker_count);
+const MAX_CAMEL_CASE_BODY_SIZE: usize = 16 * 1024 * 1024;
 
-    let app = Router::new()
-        .route("/pull", post(pull))
-        .route("/stats", get(stats))
-        .with_state(state);
+// fields whose value is a map keyed by data (image kinds, domains), not a struct
+const DATA_MAP_FIELDS: &[&str] = &["by_kind", "dns_override"];
 
-    let host = "0.0.0.0:3000";
-    info!("starting server on {}!", host);
-    let listener = tokio::net::TcpListener::bind(host).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+// routes that respond with such a map directly
+const DATA_MAP_ROUTES: &[&str] = &["/stats/by-kind"];
 
-    Ok(())
+// `rename` is whether this object's own keys are field names
+fn camel_case_keys(value: serde_json::Value, rename: bool) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    // a data map's own keys stay as they are, the values in it are structs again
+                    let is_data_map = rename && DATA_MAP_FIELDS.contains(&key.as_str());
+                    let value = camel_case_keys(value, !is_data_map);
+                    let key = if rename { to_camel_case(&key) } else { key };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        serde_json::Value::Array(items) => serde_json::Value::Array(
+            items.into_iter().map(|x| camel_case_keys(x, true)).collect(),
+        ),
+        other => other,
+    }
 }
 
-struct AppError(anyhow::Error);
+fn to_camel_case(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut upper_next = false;
+    for c in key.chars() {
+        if c == '_' && !out.is_empty() {
+            upper_next = true;
+        } else if upper_next {
+            out.extend(c.to_uppercase());
+            upper_next = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
 
+// the public subset of `ImageMeta`, who uploaded it and such stays internal
 #[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
+pub struct ImageMetaResponse {
+    id: String,
+    url: String,
+    kind: ImageKind,
+    width: i32,
+    height: i32,
+    file_size: i32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    uploaded_at: Option<OffsetDateTime>,
+    content_type: String,
+    original_url: Option<String>,
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        error!("error handling request: {}", self.0);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: self.0.to_string(),
-            }),
-        )
-            .into_response()
+impl From<ImageMeta> for ImageMetaResponse {
+    fn from(image: ImageMeta) -> ImageMetaResponse {
+        ImageMetaResponse {
+            id: image.id,
+            url: image.url,
+            kind: image.kind,
+            width: image.width,
+            height: image.height,
+            file_size: image.file_size,
+            uploaded_at: image.uploaded_at,
+            content_type: image.content_type,
+            original_url: image.original_url,
+        }
     }
 }
 
-impl IntoResponse for PKAvatarError {
-    fn into_response(self) -> Response {
-        let status_code = match self {
-            PKAvatarError::InternalError(_) | PKAvatarError::NetworkError(_) => {
-                StatusCode::INTERNAL_SERVER_ERROR
-            }
-            _ => StatusCode::BAD_REQUEST,
-        };
+pub async fn get_image(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ImageMetaResponse>, PKAvatarError> {
+    let image = db::get_by_id(&state.pool, &id)
+        .await?
+        .ok_or(PKAvatarError::ImageNotFound)?;
+    // callers check on images they're still using, so this counts as an access
+    state.access_log.record(&image.id);
+    Ok(Json(image.into()))
+}
 
-        // print inner error if otherwise hidden
-        error!("error: {}", self.source().unwrap_or(&self));
+// lets callers check whether an attachment's been pulled already without pulling it
+pub async fn get_image_by_attachment(
+    State(state): State<AppState>,
+    Path(attachment_id): Path<String>,
+) -> Result<Json<ImageMetaResponse>, PKAvatarError> {
+    // parsed here instead of with `Path<u64>` so a bad id gets the usual json error
+    let attachment_id = attachment_id
+        .parse::<u64>()
+        .map_err(|_| PKAvatarError::InvalidAttachmentId(attachment_id))?;
+    let image = db::get_by_attachment_id(&state.pool, attachment_id)
+        .await?
+        .ok_or(PKAvatarError::ImageNotFound)?;
+    state.access_log.record(&image.id);
+    Ok(Json(image.into()))
+}
 
-        (
-            status_code,
-            Json(ErrorResponse {
-                error: self.to_string(),
-            }),
+pub async fn image_variants(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<ImageVariant>>, PKAvatarError> {
+    let variants = db::get_image_variants(&state.pool, &id).await?;
+    if variants.is_empty() {
+        return Err(PKAvatarError::ImageNotFound);
+    }
+    Ok(Json(variants))
+}
+
+// everything the http server answers, layers included. rate limits read the client ip from `ConnectInfo`
+fn router(state: AppState) -> Router<AppState> {
+    let read_routes = Router::new()
+        .route("/stats", get(stats))
+        .route("/stats/by-kind", get(stats_by_kind))
+        .route("/stats/timeline", get(stats_timeline))
+        .route("/stats/dimensions", get(stats_dimensions))
+        .route("/image/by-hash-prefix/:prefix", get(get_by_hash_prefix))
+        .route("/image/by-attachment/:attachment_id", get(get_image_by_attachment))
+        .route("/image/:id", get(get_image))
+        .route("/image/:id/variants", get(image_variants))
+        .route_layer(middleware::from_fn_with_state(
+            state.read_limiter.clone(),
+            rate_limit::rate_limit,
+        ));
+
+    let api_routes = Router::new()
+        .route(
+            "/pull",
+            post(pull)
+                .layer(middleware::from_fn_with_state(state.clone(), verify_signature))
+                .layer(middleware::from_fn_with_state(
+                    state.pull_limiter.clone(),
+                    rate_limit::rate_limit,
+                )),
         )
-            .into_response()
+        .route(
+            "/pull/batch",
+            post(pull_batch)
+                .layer(middleware::from_fn_with_state(state.clone(), verify_signature))
+                .layer(middleware::from_fn_with_state(
+                    state.batch_pull_limiter.clone(),
+                    rate_limit::rate_limit,
+                )),
+        )
+        .route(
+            "/upload",
+            post(upload)
+                // axum's default limit is 2 MB, leave some room for the rest of the form
+                .layer(DefaultBodyLimit::max(
+                    (max_upload_size(&state.config) + UPLOAD_FORM_OVERHEAD) as usize,
+                ))
+                .layer(middleware::from_fn_with_state(state.clone(), verify_upload_signature))
+                .layer(middleware::from_fn_with_state(
+                    state.pull_limiter.clone(),
+                    rate_limit::rate_limit,
+                )),
+        )
+        .merge(read_routes)
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_bearer_token));
+
+    let mut app = Router::new()
+        .merge(api_routes)
+        // no auth or rate limits, these get polled by monitoring
+        .route("/health", get(health))
+        .route("/metrics", get(prometheus_metrics))
+        .nest("/admin", admin::router(state.clone()));
+
+    if state.config.content_hash_header {
+        app = app.layer(middleware::from_fn(content_hash_header));
+    }
+
+    if state.config.json_naming == Some(JsonNaming::CamelCase) {
+        app = app.layer(middleware::from_fn(camel_case_json));
+    }
+
+    if state.config.enable_request_decompression {
+        // the default body limit is enforced while the extractor reads the body,
+        // i.e. on the decompressed bytes, so this can't be used to sneak in a zip bomb
+        app = app.layer(RequestDecompressionLayer::new());
+    }
+
+    if state.config.response_compression {
+        let min_size = state.config.compression_min_size_bytes.unwrap_or(1024);
+        // images are already compressed, no point spending cpu on them again
+        let predicate = SizeAbove::new(u16::try_from(min_size).unwrap_or(u16::MAX))
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::SSE);
+        app = app.layer(CompressionLayer::new().compress_when(predicate));
     }
+
+    #[cfg(feature = "sentry")]
+    {
+        use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
+        app = app
+            .layer(SentryHttpLayer::with_transaction())
+            .layer(NewSentryLayer::<axum::extract::Request>::new_from_top());
+    }
+
+    app
 }
 
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+// `/admin` has its own token, and health/metrics stay open for monitoring
+async fn require_bearer_token(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: Next,
+) -> Result<Response, PKAvatarError> {
+    let Some(expected) = &state.config.bearer_token else {
+        return Ok(next.run(req).await);
+    };
+
+    let provided = req
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if bool::from(expected.as_bytes().ct_eq(provided.as_bytes())) => {
+            Ok(next.run(req).await)
+        }
+        _ => Err(PKAvatarError::Unauthorized),
     }
 }
 
-#[derive(Deserialize, Clone)]
-struct Config {
-    db: String,
+// pull requests are tiny, anything near this isn't one
+const MAX_SIGNED_BODY_SIZE: usize = 64 * 1024;
 
-    #[serde(default)] // default 5
-    db_connections: Option<u32>,
-    s3: S3Config,
-    base_url: String,
+// `X-PK-Signature: sha256=<hex hmac-sha256 of the body>`, only checked if `api_secret` is set.
+// the signature is over the body as the handler sees it, i.e. after request decompression
+async fn verify_signature(State(state): State<AppState>, req: axum::extract::Request, next: Next) -> Response {
+    check_signature(&state, req, next, MAX_SIGNED_BODY_SIZE).await
+}
 
-    #[serde(default)]
-    migrate_worker_count: u32,
+// same as `verify_signature`, the signature covers the whole multipart body.
+// /upload's handler buffers the file anyway, this only makes it happen a step earlier
+async fn verify_upload_signature(State(state): State<AppState>, req: axum::extract::Request, next: Next) -> Response {
+    let max_size = (max_upload_size(&state.config) + UPLOAD_FORM_OVERHEAD) as usize;
+    check_signature(&state, req, next, max_size).await
 }
 
-#[derive(Deserialize, Clone)]
-struct S3Config {
-    bucket: String,
-    application_id: String,
-    application_key: String,
-    endpoint: String,
+async fn check_signature(state: &AppState, req: axum::extract::Request, next: Next, max_size: usize) -> Response {
+    let Some(secret) = &state.config.api_secret else {
+        return next.run(req).await;
+    };
+
+    let provided = req
+        .headers()
+        .get("x-pk-signature")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("sha256="))
+        .and_then(|x| data_encoding::HEXLOWER_PERMISSIVE.decode(x.as_bytes()).ok());
+    let Some(provided) = provided else {
+        return PKAvatarError::Unauthorized.into_response();
+    };
+
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, max_size).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(&bytes);
+    let expected = mac.finalize().into_bytes();
+    if !bool::from(expected.as_slice().ct_eq(&provided)) {
+        return PKAvatarError::Unauthorized.into_response();
+    }
+
+    next.run(axum::extract::Request::from_parts(parts, axum::body::Body::from(bytes))).await
+}
+
+// shorter than this and we'd be pulling half the table
+const MIN_HASH_PREFIX_LENGTH: usize = 8;
+
+#[derive(Serialize)]
+pub struct AmbiguousPrefixResponse {
+    matches: Vec<String>,
+}
+
+pub async fn get_by_hash_prefix(
+    State(state): State<AppState>,
+    Path(prefix): Path<String>,
+) -> Result<Response, PKAvatarError> {
+    // also keeps `%` and `_` out of the like pattern
+    if prefix.len() < MIN_HASH_PREFIX_LENGTH || !prefix.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(PKAvatarError::InvalidHashPrefix(prefix, MIN_HASH_PREFIX_LENGTH));
+    }
+
+    let mut matches = db::get_by_id_prefix(&state.pool, &prefix.to_lowercase()).await?;
+    match matches.len() {
+        0 => Err(PKAvatarError::ImageNotFound),
+        1 => {
+            let image = matches.remove(0);
+            Ok((Extension(ImageId(image.id.clone(), image.hash_algorithm)), Json(image)).into_response())
+        }
+        _ => Ok((
+            StatusCode::MULTIPLE_CHOICES,
+            Json(AmbiguousPrefixResponse {
+                matches: matches.into_iter().map(|x| x.id).collect(),
+            }),
+        )
+            .into_response()),
+    }
+}
+
+#[derive(Clone)]
+pub struct CachedStats {
+    stats: Stats,
+    by_kind: HashMap<ImageKind, Stats>,
+    dead_letter_count: i64,
+    refreshed_at: std::time::Instant,
+}
+
+async fn fetch_stats(pool: &PgPool) -> Result<CachedStats, PKAvatarError> {
+    Ok(CachedStats {
+        stats: db::get_stats(pool).await?,
+        by_kind: db::get_stats_by_kind(pool).await?,
+        dead_letter_count: db::get_dead_letter_count(pool).await?,
+        refreshed_at: std::time::Instant::now(),
+    })
+}
+
+async fn cached_stats(state: &AppState) -> Result<CachedStats, PKAvatarError> {
+    if let Some(cached) = state.stats_cache.read().await.as_ref() {
+        return Ok(cached.clone());
+    }
+
+    // cache hasn't been filled yet (just started up), do it live
+    fetch_stats(&state.pool).await
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    #[serde(flatten)]
+    stats: Stats,
+    by_kind: HashMap<ImageKind, Stats>,
+    // migrate queue items that gave up after too many transient failures
+    dead_letter_count: i64,
+    stats_cache_age_seconds: u64,
+}
+
+pub async fn stats(State(state): State<AppState>) -> Result<Json<StatsResponse>, PKAvatarError> {
+    let cached = cached_stats(&state).await?;
+    Ok(Json(StatsResponse {
+        stats: cached.stats,
+        by_kind: cached.by_kind,
+        dead_letter_count: cached.dead_letter_count,
+        stats_cache_age_seconds: cached.refreshed_at.elapsed().as_secs(),
+    }))
+}
+
+pub async fn stats_by_kind(
+    State(state): State<AppState>,
+) -> Result<Json<HashMap<ImageKind, Stats>>, PKAvatarError> {
+    Ok(Json(cached_stats(&state).await?.by_kind))
+}
+
+// `get_stats` is a full table scan, so don't run it on every request
+async fn refresh_stats_task(state: AppState) {
+    let interval = Duration::from_secs(state.config.stats_refresh_interval_secs.unwrap_or(60));
+    loop {
+        let time_before = std::time::Instant::now();
+        match fetch_stats(&state.pool).await {
+            Ok(stats) => {
+                *state.stats_cache.write().await = Some(stats);
+                info!("refreshed stats in {} ms", time_before.elapsed().as_millis());
+            }
+            Err(e) => error!("error refreshing stats: {}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+const MAX_TIMELINE_DAYS: u32 = 365;
+
+#[derive(Deserialize)]
+pub struct TimelineQuery {
+    granularity: Option<TimelineGranularity>,
+    days: Option<u32>,
+}
+
+pub async fn stats_timeline(
+    State(state): State<AppState>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<Vec<TimelineBucket>>, PKAvatarError> {
+    let granularity = query.granularity.unwrap_or(TimelineGranularity::Hour);
+    let days = query.days.unwrap_or(7).min(MAX_TIMELINE_DAYS);
+    Ok(Json(
+        db::get_upload_timeline(&state.pool, granularity, days).await?,
+    ))
+}
+
+pub async fn stats_dimensions(
+    State(state): State<AppState>,
+) -> Result<Json<DimensionHistogram>, PKAvatarError> {
+    Ok(Json(db::get_dimension_histogram(&state.pool).await?))
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    status: &'static str,
+    db: bool,
+    storage: bool,
+}
+
+pub async fn health(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
+    let timeout = Duration::from_millis(state.config.health_check_timeout_ms.unwrap_or(2000));
+
+    // run both at once, a slow db shouldn't eat into the storage check's budget
+    let (db_res, storage_res) = tokio::join!(
+        tokio::time::timeout(timeout, db::check(&state.pool)),
+        tokio::time::timeout(timeout, state.storer.check_storage()),
+    );
+
+    let db_ok = match db_res {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            warn!("health check: database error: {}", e);
+            false
+        }
+        Err(_) => {
+            warn!("health check: database timed out after {} ms", timeout.as_millis());
+            false
+        }
+    };
+    let storage_ok = match storage_res {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            warn!("health check: storage error: {}", e);
+            false
+        }
+        Err(_) => {
+            warn!("health check: storage timed out after {} ms", timeout.as_millis());
+            false
+        }
+    };
+
+    let (status_code, status) = if db_ok && storage_ok {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    (
+        status_code,
+        Json(HealthResponse {
+            status,
+            db: db_ok,
+            storage: storage_ok,
+        }),
+    )
+}
+
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+// discord's upload limit is well under the top bucket
+const SIZE_BUCKETS: &[f64] = &[
+    16.0 * 1024.0,
+    64.0 * 1024.0,
+    256.0 * 1024.0,
+    1024.0 * 1024.0,
+    4.0 * 1024.0 * 1024.0,
+    16.0 * 1024.0 * 1024.0,
+    64.0 * 1024.0 * 1024.0,
+];
+
+// prometheus text format. pool stats and the queue length are instantaneous, so they're read right when scraped
+pub async fn prometheus_metrics(State(state): State<AppState>) -> String {
+    let idle = state.pool.num_idle() as f64;
+    let total = state.pool.size() as f64;
+    metrics::gauge!("db_connections_total", "state" => "active").set(total - idle);
+    metrics::gauge!("db_connections_total", "state" => "idle").set(idle);
+    metrics::gauge!("db_connections_max").set(state.pool.options().get_max_connections() as f64);
+    // a failed count shouldn't take the rest of the scrape down with it
+    match db::get_queue_length(&state.pool).await {
+        Ok(length) => metrics::gauge!("pkavatars_migrate_queue_length").set(length as f64),
+        Err(e) => warn!("error getting queue length for metrics: {}", e),
+    }
+
+    state.metrics.render()
+}
+
+fn load_config() -> anyhow::Result<Config> {
+    config::ConfigBuilder::<DefaultState>::default()
+        .add_source(config::File::new("config", FileFormat::Toml).required(false))
+        .add_source(
+            config::Environment::with_prefix("PK_AVATAR")
+                .prefix_separator("__")
+                .separator("__"),
+        )
+        .build()?
+        .try_deserialize::<Config>()
+        .map_err(Into::into)
+        .and_then(validate_config)
+}
+
+fn validate_config(config: Config) -> anyhow::Result<Config> {
+    validate_base_url(&config.base_url)?;
+    if let Some(base_urls) = &config.base_urls {
+        if base_urls.is_empty() {
+            anyhow::bail!("base_urls must not be empty if set");
+        }
+        for base_url in base_urls {
+            validate_base_url(base_url)?;
+        }
+    }
+
+    reqwest::Url::parse(&config.s3.endpoint).context("s3.endpoint is not a valid url")?;
+
+    // gets formatted straight into sql, identifiers can't be bound as parameters
+    if let Some(schema) = &config.db_schema {
+        if !db::is_valid_schema_name(schema) {
+            anyhow::bail!("db_schema must be lowercase letters, digits and underscores (got {})", schema);
+        }
+    }
+
+    match &config.base_urls {
+        Some(base_urls) => info!(
+            "using base urls {}",
+            base_urls.iter().map(|x| redact_url(x)).collect::<Vec<_>>().join(", ")
+        ),
+        None => info!("using base url {}", redact_url(&config.base_url)),
+    }
+    Ok(config)
+}
+
+fn validate_base_url(url: &str) -> anyhow::Result<()> {
+    // image urls are built as `{base_url}{path}`, and path doesn't start with a slash
+    let parsed = reqwest::Url::parse(url).context("base_url is not a valid url")?;
+    if parsed.scheme() != "https" {
+        anyhow::bail!("base_url must be https (got {})", redact_url(url));
+    }
+    if !url.ends_with('/') {
+        anyhow::bail!("base_url must end with a slash (got {})", redact_url(url));
+    }
+    Ok(())
+}
+
+fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut url) => {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        Err(_) => "[invalid url]".to_string(),
+    }
+}
+
+impl AppState {
+    // with multiple base urls, the same image always has to end up on the same one (cdn caches)
+    pub fn url_for_path(&self, hash: &str, path: &str) -> String {
+        let base_url = match self.config.base_urls.as_deref() {
+            Some(base_urls) if !base_urls.is_empty() => shard_base_url(base_urls, hash),
+            _ => &self.config.base_url,
+        };
+        format!("{}{}", base_url, path)
+    }
+}
+
+// only looks at the first character of the hash, so it doesn't depend on the hash algorithm or the id length.
+// base_urls can't be empty (checked on startup)
+fn shard_base_url<'a>(base_urls: &'a [String], hash: &str) -> &'a str {
+    let shard = hash.as_bytes().first().copied().unwrap_or(0) as usize % base_urls.len();
+    &base_urls[shard]
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    storer: Storer,
+    puller: Arc<dyn ImagePuller>,
+    pool: PgPool,
+    config: Arc<Config>,
+    stats_cache: Arc<RwLock<Option<CachedStats>>>,
+    recent_pulls: Arc<RecentPullLog>,
+    in_flight: Arc<InFlightPulls>,
+    attachment_cache: Arc<AttachmentCache>,
+    idempotency: Arc<IdempotencyCache>,
+    access_log: Arc<AccessLog>,
+    recent_errors: Arc<RecentErrors>,
+    metrics: PrometheusHandle,
+    pull_limiter: Arc<RateLimiter>,
+    batch_pull_limiter: Arc<RateLimiter>,
+    read_limiter: Arc<RateLimiter>,
+    // flipped to true once the http server has stopped, background workers should wrap up
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+// not using #[tokio::main] so the runtime can be tuned from config
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let config = load_config()?;
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = config.tokio_worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    // this is the pool `process_async` runs on, so it effectively caps parallel encodes
+    if let Some(blocking_threads) = config.tokio_blocking_threads {
+        runtime.max_blocking_threads(blocking_threads);
+    }
+    runtime.build()?.block_on(real_main(config))
+}
+
+async fn real_main(config: Config) -> anyhow::Result<()> {
+    #[cfg(feature = "sentry")]
+    let _sentry_guard = config.sentry_dsn.as_ref().map(|dsn| {
+        sentry::init((
+            dsn.as_str(),
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                traces_sample_rate: config.sentry_traces_sample_rate.unwrap_or(0.0) as f32,
+                ..Default::default()
+            },
+        ))
+    });
+
+    let storer = Storer::new(&config)?;
+    let puller = Arc::new(Puller::new(&config)?);
+
+    info!("connecting to database...");
+    let max_lifetime = Duration::from_secs(config.db_max_lifetime_secs.unwrap_or(30 * 60));
+    let schema = config.db_schema.clone();
+    let pool = PgPoolOptions::new()
+        .max_connections(config.db_connections.unwrap_or(5))
+        // ping before handing out a connection, firewalls like to silently drop idle ones
+        .test_before_acquire(config.db_keepalive)
+        .idle_timeout(Duration::from_secs(config.db_idle_timeout_secs.unwrap_or(10 * 60)))
+        .max_lifetime(max_lifetime)
+        .after_release(move |_conn, meta| {
+            Box::pin(async move {
+                if meta.age >= max_lifetime {
+                    info!("replacing database connection after {} s (max lifetime)", meta.age.as_secs());
+                    return Ok(false);
+                }
+                Ok(true)
+            })
+        })
+        // search_path is per connection, so every new one needs it
+        .after_connect(move |conn, _meta| {
+            let schema = schema.clone();
+            Box::pin(async move {
+                if let Some(schema) = schema {
+                    db::set_search_path(conn, &schema).await?;
+                }
+                Ok(())
+            })
+        })
+        .connect(&config.db)
+        .await?;
+    db::init(&pool, config.db_schema.as_deref()).await?;
+
+    let rate_limits = config.rate_limit.clone();
+    let recent_errors = Arc::new(RecentErrors::new(
+        config.recent_errors_max_size.unwrap_or(recent_errors::DEFAULT_MAX_SIZE),
+    ));
+    let attachment_cache = Arc::new(AttachmentCache::new(
+        config.cache_size.unwrap_or(attachment_cache::DEFAULT_CAPACITY),
+    ));
+    let state = AppState {
+        storer,
+        puller,
+        pool,
+        config: Arc::new(config),
+        stats_cache: Arc::new(RwLock::new(None)),
+        recent_pulls: Arc::new(RecentPullLog::default()),
+        in_flight: Arc::new(InFlightPulls::default()),
+        attachment_cache,
+        idempotency: Arc::new(IdempotencyCache::default()),
+        access_log: Arc::new(AccessLog::default()),
+        recent_errors,
+        metrics: PrometheusBuilder::new()
+            // without buckets these would be rendered as summaries
+            .set_buckets_for_metric(Matcher::Suffix("_duration_seconds".to_string()), DURATION_BUCKETS)?
+            .set_buckets_for_metric(Matcher::Prefix("pkavatars_image_bytes".to_string()), SIZE_BUCKETS)?
+            .install_recorder()
+            .context("error installing metrics recorder")?,
+        pull_limiter: Arc::new(RateLimiter::new("pull", rate_limits.pull_rpm)),
+        batch_pull_limiter: Arc::new(RateLimiter::new("batch_pull", rate_limits.batch_pull_rpm)),
+        read_limiter: Arc::new(RateLimiter::new("read", rate_limits.read_rpm)),
+        shutdown: Arc::new(watch::channel(false).0),
+    };
+
+    tokio::spawn(pull_log::cleanup_task(state.recent_pulls.clone()));
+    tokio::spawn(idempotency::cleanup_task(state.idempotency.clone()));
+    tokio::spawn(access_log::flush_task(state.access_log.clone(), state.pool.clone()));
+    tokio::spawn(refresh_stats_task(state.clone()));
+    tokio::spawn(rate_limit::cleanup_task(vec![
+        state.pull_limiter.clone(),
+        state.batch_pull_limiter.clone(),
+        state.read_limiter.clone(),
+    ]));
+    let migrate_workers =
+        migrate::spawn_migrate_workers(Arc::new(state.clone()), state.config.migrate_worker_count);
+
+    let app = router(state.clone());
+
+    let host = "0.0.0.0:3000";
+    log_startup_info(&state.config, host);
+
+    let shutdown = state.shutdown.clone();
+    let shutdown_timeout = Duration::from_secs(
+        state.config.shutdown_timeout_secs.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+    );
+    let app = app.with_state(state);
+
+    info!("starting server on {}!", host);
+    let listener = tokio::net::TcpListener::bind(host).await.unwrap();
+    let (signal_tx, signal_rx) = tokio::sync::oneshot::channel();
+    // one deadline for the whole shutdown, requests and workers share it
+    let deadline = futures::FutureExt::shared(async move { signal_rx.await.ok() });
+    // rate limits are per client ip
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown_signal().await;
+                info!("shutting down");
+                let _ = signal_tx.send(tokio::time::Instant::now() + shutdown_timeout);
+                // workers finish the item they're on (so nothing gets half-written) and then exit
+                shutdown.send_replace(true);
+            }
+        })
+        .into_future();
+
+    // stops accepting connections right away, then waits for the requests that are still running
+    tokio::select! {
+        res = server => res?,
+        _ = async {
+            match deadline.clone().await {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        } => warn!("requests didn't finish within {} s, exiting anyway", shutdown_timeout.as_secs()),
+    }
+
+    let wait_for_workers = async {
+        for handle in migrate_workers {
+            let _ = handle.await;
+        }
+    };
+    // whatever the requests left of the timeout
+    let deadline = deadline
+        .await
+        .unwrap_or_else(|| tokio::time::Instant::now() + shutdown_timeout);
+    if tokio::time::timeout_at(deadline, wait_for_workers).await.is_err() {
+        warn!("migrate workers didn't stop within {} s of shutting down, exiting anyway", shutdown_timeout.as_secs());
+    }
+
+    info!("shutdown complete");
+    Ok(())
+}
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for ctrl-c");
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for sigterm")
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// one line per thing so log aggregators can pick the fields apart
+fn log_startup_info(config: &Config, host: &str) {
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        commit = option_env!("GIT_COMMIT").unwrap_or("unknown"),
+        "pluralkit-avatars starting up"
+    );
+    info!(listen_address = host, "server config");
+    info!(
+        db = %redact_dsn(&config.db),
+        db_connections = config.db_connections.unwrap_or(5),
+        "database config"
+    );
+    info!(
+        storage_backend = ?config.storage_backend,
+        s3_endpoint = %config.s3.endpoint,
+        s3_bucket = %config.s3.bucket,
+        base_url = %redact_url(&config.base_url),
+        "storage config"
+    );
+    info!(
+        migrate_worker_count = config.migrate_worker_count,
+        migrate_min_idle_ms = config.migrate_min_idle_ms.unwrap_or(migrate::DEFAULT_MIN_IDLE_MS),
+        migrate_max_idle_ms = config.migrate_max_idle_ms.unwrap_or(migrate::DEFAULT_MAX_IDLE_MS),
+        max_migration_attempts = config.max_migration_attempts.unwrap_or(migrate::DEFAULT_MAX_ATTEMPTS),
+        migrate_retry_backoff_secs = config.migrate_retry_backoff_secs.unwrap_or(migrate::DEFAULT_RETRY_BACKOFF_SECS),
+        "migrate config"
+    );
+    let avatar_limits = config.limits_for_kind(ImageKind::Avatar);
+    let banner_limits = config.limits_for_kind(ImageKind::Banner);
+    info!(
+        avatar_max_file_size = avatar_limits.max_bytes,
+        banner_max_file_size = banner_limits.max_bytes,
+        gif_max_file_size = config.gif_max_size_bytes.unwrap_or(pull::DEFAULT_GIF_MAX_SIZE),
+        avatar_max_dimension = avatar_limits.max_dimension,
+        banner_max_dimension = banner_limits.max_dimension,
+        webp_quality = process::DEFAULT_WEBP_QUALITY,
+        output_format = ?config.output_format,
+        adaptive_quality = config.adaptive_quality,
+        "limits"
+    );
+}
+
+// only keep host, port and database name, the rest (user/password/params) isn't anyone's business
+fn redact_dsn(dsn: &str) -> String {
+    match reqwest::Url::parse(dsn) {
+        Ok(url) => format!(
+            "{}:{}{}",
+            url.host_str().unwrap_or(""),
+            url.port().unwrap_or(5432),
+            url.path()
+        ),
+        Err(_) => "[invalid dsn]".to_string(),
+    }
+}
+
+struct AppError(anyhow::Error);
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    error: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        error!("error handling request: {}", self.0);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: self.0.to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+impl PKAvatarError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            PKAvatarError::InternalError(_)
+            | PKAvatarError::NetworkError(_)
+            | PKAvatarError::NetworkTimeout => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            PKAvatarError::ImageNotFound | PKAvatarError::UploadNotFound => StatusCode::NOT_FOUND,
+            PKAvatarError::DatabaseError(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND,
+            PKAvatarError::DatabaseError(
+                sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed
+                | sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_),
+            ) => StatusCode::SERVICE_UNAVAILABLE,
+            PKAvatarError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PKAvatarError::Unauthorized => StatusCode::UNAUTHORIZED,
+            PKAvatarError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            PKAvatarError::IdempotencyKeyMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            PKAvatarError::IdempotencyKeyInProgress => StatusCode::CONFLICT,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl IntoResponse for PKAvatarError {
+    fn into_response(self) -> Response {
+        let status_code = self.status_code();
+
+        // print inner error if otherwise hidden
+        error!("error: {}", self.source().unwrap_or(&self));
+
+        #[cfg(feature = "sentry")]
+        if status_code == StatusCode::INTERNAL_SERVER_ERROR {
+            sentry::capture_error(&self);
+        }
+
+        (
+            status_code,
+            Json(ErrorResponse {
+                error: self.to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct Config {
+    db: String,
+
+    #[serde(default)] // default 5
+    db_connections: Option<u32>,
+
+    #[serde(default = "default_true")]
+    db_keepalive: bool,
+
+    #[serde(default)] // default 600
+    db_idle_timeout_secs: Option<u64>,
+
+    #[serde(default)] // default 1800
+    db_max_lifetime_secs: Option<u64>,
+
+    // lets several environments share one database, created on startup if it doesn't exist
+    #[serde(default)] // default public
+    db_schema: Option<String>,
+
+    // "s3" or "b2". b2 reuses the s3 config: application_id/application_key as the key, bucket as the bucket id
+    #[serde(default)] // default s3
+    storage_backend: StorageBackend,
+    s3: S3Config,
+    base_url: String,
+
+    #[serde(default)] // no limits
+    rate_limit: RateLimitConfig,
+
+    #[serde(default)]
+    limits: LimitsConfig,
+
+    // shards images across several cdn origins by hash, overrides base_url if set
+    #[serde(default)]
+    base_urls: Option<Vec<String>>,
+
+    #[serde(default)]
+    migrate_worker_count: u32,
+
+    // idle workers poll the queue starting at the min interval, doubling up to the max while it stays empty
+    #[serde(default)] // default 100
+    migrate_min_idle_ms: Option<u64>,
+
+    #[serde(default)] // default 30000
+    migrate_max_idle_ms: Option<u64>,
+
+    // after this many transient failures a queue item goes to `image_queue_dead` instead of being retried again
+    #[serde(default)] // default 5
+    max_migration_attempts: Option<i32>,
+
+    // how long a queue item waits after its first transient failure, doubling with every one after that
+    #[serde(default)] // default 60
+    migrate_retry_backoff_secs: Option<u64>,
+
+    #[cfg(feature = "sentry")]
+    #[serde(default)]
+    sentry_dsn: Option<String>,
+
+    // 0.0-1.0, default 0 (no transaction tracing)
+    #[cfg(feature = "sentry")]
+    #[serde(default)]
+    sentry_traces_sample_rate: Option<f64>,
+
+    #[serde(default)] // default = number of cpus
+    tokio_worker_threads: Option<usize>,
+
+    #[serde(default)] // default 512
+    tokio_blocking_threads: Option<usize>,
+
+    #[serde(default)] // default 60
+    stats_refresh_interval_secs: Option<u64>,
+
+    #[serde(default)] // default 2000
+    health_check_timeout_ms: Option<u64>,
+
+    // bearer token for /admin routes, they're disabled if unset
+    #[serde(default)]
+    admin_token: Option<String>,
+
+    // if set, everything except /health, /metrics and /admin needs `Authorization: Bearer <token>`.
+    // usually set through the environment, as PK_AVATAR__BEARER_TOKEN
+    #[serde(default)]
+    bearer_token: Option<String>,
+
+    // if set, POST /pull requires an `X-PK-Signature` hmac of the body made with this
+    #[serde(default)]
+    api_secret: Option<String>,
+
+    #[serde(default)]
+    quantize_before_encode: bool,
+
+    // "none" or "diffusion"
+    #[serde(default)] // default diffusion
+    quantize_dither_mode: Option<QuantizeDitherMode>,
+
+    // "webp" or "avif", for static images. avif is smaller but a lot slower to encode
+    #[serde(default)] // default webp
+    output_format: OutputFormat,
+
+    #[serde(default)] // default 70
+    avif_quality: Option<f32>,
+
+    // "sha256" or "blake3", what new image ids are derived from. existing images keep theirs (it's stored per image),
+    // but they won't be deduplicated against anything stored after a switch
+    #[serde(default)] // default sha256
+    hash_algorithm: HashAlgorithm,
+
+    // also try a lossless encode and keep it if it's smaller. doubles encoding time
+    #[serde(default)]
+    lossless_comparison: bool,
+
+    // compare the encoded output against the input (ssim) and re-encode at higher quality if it's too far off.
+    // costs an extra decode per image, plus an encode per retry
+    #[serde(default)]
+    quality_assessment: bool,
+
+    #[serde(default)] // default 0.9
+    quality_min_score: Option<f32>,
+
+    // pick webp quality based on how compressed the input already is, instead of a flat 90.
+    // static images only, animations always get 90
+    #[serde(default)]
+    adaptive_quality: bool,
+
+    // kinds to always store at exactly their target size, eg. ["avatar"].
+    // smaller images get scaled up, non-square ones padded with transparency
+    #[serde(default)]
+    normalize_to_target_size: Vec<ImageKind>,
+
+    // unsharp mask after downscaling, images that are already small enough are left alone
+    #[serde(default)]
+    sharpen_after_resize: bool,
+
+    #[serde(default)] // default 0.5
+    sharpen_sigma: Option<f32>,
+
+    // convert images with an embedded (rgb) icc profile to srgb before encoding
+    #[serde(default = "default_true")]
+    normalize_color_space: bool,
+
+    // tag encoded webps as srgb, so wide-gamut displays don't stretch them.
+    // (the pixels are srgb anyway with normalize_color_space)
+    #[serde(default = "default_true")]
+    embed_srgb_profile: bool,
+
+    // apply exif orientation so photos from phones don't end up sideways
+    #[serde(default = "default_true")]
+    auto_rotate: bool,
+
+    // landscape avatars end up as a thin strip once discord crops them to a circle
+    #[serde(default)]
+    reject_landscape_avatars: bool,
+
+    // with reject_landscape_avatars, only log them instead of failing the pull
+    #[serde(default)]
+    landscape_avatar_warning_only: bool,
+
+    // add `X-Content-Hash: <algorithm>-trunc128:<image id>` to responses for a single image
+    #[serde(default = "default_true")]
+    content_hash_header: bool,
+
+    // "snake_case" or "camel_case", for the keys in json responses
+    #[serde(default)] // default snake_case
+    json_naming: Option<JsonNaming>,
+
+    // gzip/br compress responses for clients that send `Accept-Encoding`
+    #[serde(default)]
+    response_compression: bool,
+
+    // smaller responses aren't worth compressing. capped at 65535
+    #[serde(default)] // default 1024
+    compression_min_size_bytes: Option<usize>,
+
+    // accept gzip/deflate/br `Content-Encoding` on request bodies.
+    // most json clients never compress requests, this is mostly useful for bulk/batch callers
+    #[serde(default)]
+    enable_request_decompression: bool,
+
+    #[serde(default)] // default 3
+    pull_headers_timeout_secs: Option<u64>,
+
+    #[serde(default)] // default 10
+    pull_body_timeout_secs: Option<u64>,
+
+    // size limit for gif inputs specifically, everything else is limited by `limits`
+    #[serde(default)] // default 20MB
+    gif_max_size_bytes: Option<u64>,
+
+    // domain -> ip address, bypasses dns for those domains when pulling
+    #[serde(default)]
+    dns_override: Option<HashMap<String, String>>,
+
+    // log (the start of) cdn response bodies for non-200 responses
+    #[serde(default)]
+    log_error_bodies: bool,
+
+    // lets clients ask for extra diagnostics (eg. `include_timing` on /pull)
+    #[serde(default)]
+    debug_mode_enabled: bool,
+
+    // how many errors /admin/recent-errors keeps around
+    #[serde(default)] // default 100
+    recent_errors_max_size: Option<usize>,
+
+    // how many items of a /pull/batch request are downloaded at once.
+    // `batch_concurrency` is the old name, from before processing had its own limit
+    #[serde(default, alias = "batch_concurrency")] // default 8
+    batch_download_concurrency: Option<usize>,
+
+    // how many downloaded items of a /pull/batch request are processed at once
+    #[serde(default)] // default 1
+    batch_process_concurrency: Option<usize>,
+
+    // attachment ids whose stored image is kept in memory for /pull, 0 = no cache.
+    // roughly 250 bytes per entry, so the default is a couple MB
+    #[serde(default)] // default 10000
+    cache_size: Option<usize>,
+
+    // how long running requests (and then migrate workers) get to finish after sigterm/sigint
+    #[serde(default)] // default 30
+    shutdown_timeout_secs: Option<u64>,
+
+    #[serde(default)] // default 512
+    log_error_body_max_bytes: Option<usize>,
+
+    #[serde(default = "default_true")]
+    follow_redirects: bool,
+
+    #[serde(default)] // default 5
+    max_redirects: Option<usize>,
+
+    // retries for network errors, timeouts and 429/5xx responses when pulling
+    #[serde(default)] // default 3
+    max_retries: Option<u32>,
+
+    // doubles with every retry, up to 30s. a Retry-After from the cdn takes precedence
+    #[serde(default)] // default 500
+    initial_backoff_ms: Option<u64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// what `GET /admin/config` shows: everything except credentials.
+// unset optional values are null, see the comments on `Config` for their defaults
+#[derive(Serialize)]
+pub struct ConfigView {
+    db: String,
+    db_connections: Option<u32>,
+    db_keepalive: bool,
+    db_idle_timeout_secs: Option<u64>,
+    db_max_lifetime_secs: Option<u64>,
+    db_schema: Option<String>,
+    storage_backend: StorageBackend,
+    s3: S3ConfigView,
+    base_url: String,
+    rate_limit: RateLimitConfig,
+    limits: LimitsConfig,
+    base_urls: Option<Vec<String>>,
+    migrate_worker_count: u32,
+    migrate_min_idle_ms: Option<u64>,
+    migrate_max_idle_ms: Option<u64>,
+    max_migration_attempts: Option<i32>,
+    migrate_retry_backoff_secs: Option<u64>,
+    #[cfg(feature = "sentry")]
+    sentry_dsn: Option<String>,
+    #[cfg(feature = "sentry")]
+    sentry_traces_sample_rate: Option<f64>,
+    tokio_worker_threads: Option<usize>,
+    tokio_blocking_threads: Option<usize>,
+    stats_refresh_interval_secs: Option<u64>,
+    health_check_timeout_ms: Option<u64>,
+    admin_token_set: bool,
+    bearer_token_set: bool,
+    api_secret_set: bool,
+    quantize_before_encode: bool,
+    quantize_dither_mode: Option<QuantizeDitherMode>,
+    output_format: OutputFormat,
+    avif_quality: Option<f32>,
+    hash_algorithm: HashAlgorithm,
+    lossless_comparison: bool,
+    quality_assessment: bool,
+    quality_min_score: Option<f32>,
+    adaptive_quality: bool,
+    normalize_to_target_size: Vec<ImageKind>,
+    sharpen_after_resize: bool,
+    sharpen_sigma: Option<f32>,
+    normalize_color_space: bool,
+    embed_srgb_profile: bool,
+    auto_rotate: bool,
+    reject_landscape_avatars: bool,
+    landscape_avatar_warning_only: bool,
+    content_hash_header: bool,
+    json_naming: Option<JsonNaming>,
+    response_compression: bool,
+    compression_min_size_bytes: Option<usize>,
+    enable_request_decompression: bool,
+    pull_headers_timeout_secs: Option<u64>,
+    pull_body_timeout_secs: Option<u64>,
+    gif_max_size_bytes: Option<u64>,
+    dns_override: Option<HashMap<String, String>>,
+    log_error_bodies: bool,
+    log_error_body_max_bytes: Option<usize>,
+    debug_mode_enabled: bool,
+    recent_errors_max_size: Option<usize>,
+    batch_download_concurrency: Option<usize>,
+    batch_process_concurrency: Option<usize>,
+    cache_size: Option<usize>,
+    shutdown_timeout_secs: Option<u64>,
+    follow_redirects: bool,
+    max_redirects: Option<usize>,
+    max_retries: Option<u32>,
+    initial_backoff_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct S3ConfigView {
+    bucket: String,
+    application_id: String,
+    application_key: &'static str,
+    endpoint: String,
+    s3_max_connections: Option<usize>,
+    s3_multipart_threshold_bytes: Option<u64>,
+    s3_upload_timeout_secs: Option<u64>,
+}
+
+impl Config {
+    pub fn limits_for_kind(&self, kind: ImageKind) -> ImageLimits {
+        self.limits.for_kind(kind)
+    }
+
+    pub fn to_view(&self) -> ConfigView {
+        ConfigView {
+            db: redact_dsn(&self.db),
+            db_connections: self.db_connections,
+            db_keepalive: self.db_keepalive,
+            db_idle_timeout_secs: self.db_idle_timeout_secs,
+            db_max_lifetime_secs: self.db_max_lifetime_secs,
+            db_schema: self.db_schema.clone(),
+            storage_backend: self.storage_backend,
+            s3: S3ConfigView {
+                bucket: self.s3.bucket.clone(),
+                application_id: self.s3.application_id.clone(),
+                application_key: "[redacted]",
+                endpoint: self.s3.endpoint.clone(),
+                s3_max_connections: self.s3.s3_max_connections,
+                s3_multipart_threshold_bytes: self.s3.s3_multipart_threshold_bytes,
+                s3_upload_timeout_secs: self.s3.s3_upload_timeout_secs,
+            },
+            base_url: self.base_url.clone(),
+            rate_limit: self.rate_limit.clone(),
+            limits: self.limits.clone(),
+            base_urls: self.base_urls.clone(),
+            migrate_worker_count: self.migrate_worker_count,
+            migrate_min_idle_ms: self.migrate_min_idle_ms,
+            migrate_max_idle_ms: self.migrate_max_idle_ms,
+            max_migration_attempts: self.max_migration_attempts,
+            migrate_retry_backoff_secs: self.migrate_retry_backoff_secs,
+            #[cfg(feature = "sentry")]
+            sentry_dsn: self.sentry_dsn.as_deref().map(redact_url),
+            #[cfg(feature = "sentry")]
+            sentry_traces_sample_rate: self.sentry_traces_sample_rate,
+            tokio_worker_threads: self.tokio_worker_threads,
+            tokio_blocking_threads: self.tokio_blocking_threads,
+            stats_refresh_interval_secs: self.stats_refresh_interval_secs,
+            health_check_timeout_ms: self.health_check_timeout_ms,
+            admin_token_set: self.admin_token.is_some(),
+            bearer_token_set: self.bearer_token.is_some(),
+            api_secret_set: self.api_secret.is_some(),
+            quantize_before_encode: self.quantize_before_encode,
+            quantize_dither_mode: self.quantize_dither_mode,
+            output_format: self.output_format,
+            avif_quality: self.avif_quality,
+            hash_algorithm: self.hash_algorithm,
+            lossless_comparison: self.lossless_comparison,
+            quality_assessment: self.quality_assessment,
+            quality_min_score: self.quality_min_score,
+            adaptive_quality: self.adaptive_quality,
+            normalize_to_target_size: self.normalize_to_target_size.clone(),
+            sharpen_after_resize: self.sharpen_after_resize,
+            sharpen_sigma: self.sharpen_sigma,
+            normalize_color_space: self.normalize_color_space,
+            embed_srgb_profile: self.embed_srgb_profile,
+            auto_rotate: self.auto_rotate,
+            reject_landscape_avatars: self.reject_landscape_avatars,
+            landscape_avatar_warning_only: self.landscape_avatar_warning_only,
+            content_hash_header: self.content_hash_header,
+            json_naming: self.json_naming,
+            response_compression: self.response_compression,
+            compression_min_size_bytes: self.compression_min_size_bytes,
+            enable_request_decompression: self.enable_request_decompression,
+            pull_headers_timeout_secs: self.pull_headers_timeout_secs,
+            pull_body_timeout_secs: self.pull_body_timeout_secs,
+            gif_max_size_bytes: self.gif_max_size_bytes,
+            dns_override: self.dns_override.clone(),
+            log_error_bodies: self.log_error_bodies,
+            log_error_body_max_bytes: self.log_error_body_max_bytes,
+            debug_mode_enabled: self.debug_mode_enabled,
+            recent_errors_max_size: self.recent_errors_max_size,
+            batch_download_concurrency: self.batch_download_concurrency,
+            batch_process_concurrency: self.batch_process_concurrency,
+            cache_size: self.cache_size,
+            shutdown_timeout_secs: self.shutdown_timeout_secs,
+            follow_redirects: self.follow_redirects,
+            max_redirects: self.max_redirects,
+            max_retries: self.max_retries,
+            initial_backoff_ms: self.initial_backoff_ms,
+        }
+    }
+}
+
+// input limits, checked before anything gets decoded
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LimitsConfig {
+    #[serde(default)] // default 8MB
+    avatar_max_bytes: Option<u64>,
+
+    #[serde(default)] // default 16MB
+    banner_max_bytes: Option<u64>,
+
+    #[serde(default)] // default 4000
+    avatar_max_dimension: Option<u32>,
+
+    #[serde(default)] // default 4000
+    banner_max_dimension: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImageLimits {
+    pub max_bytes: u64,
+    // for both width and height
+    pub max_dimension: u32,
+}
+
+impl LimitsConfig {
+    pub fn for_kind(&self, kind: ImageKind) -> ImageLimits {
+        match kind {
+            ImageKind::Avatar => ImageLimits {
+                max_bytes: self.avatar_max_bytes.unwrap_or(pull::DEFAULT_MAX_SIZE),
+                max_dimension: self.avatar_max_dimension.unwrap_or(process::DEFAULT_MAX_DIMENSION),
+            },
+            ImageKind::Banner => ImageLimits {
+                max_bytes: self.banner_max_bytes.unwrap_or(pull::DEFAULT_BANNER_MAX_SIZE),
+                max_dimension: self.banner_max_dimension.unwrap_or(process::DEFAULT_MAX_DIMENSION),
+            },
+        }
+    }
+}
+
+// requests per minute per client ip, 0 = unlimited
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pull_rpm: u32,
+
+    #[serde(default)]
+    batch_pull_rpm: u32,
+
+    // GET endpoints (stats and image lookups)
+    #[serde(default)]
+    read_rpm: u32,
+}
+
+#[derive(Deserialize, Clone)]
+struct S3Config {
+    bucket: String,
+    application_id: String,
+    application_key: String,
+    endpoint: String,
+
+    #[serde(default)] // unlimited
+    s3_max_connections: Option<usize>,
+
+    #[serde(default)] // default 0 (never use multipart)
+    s3_multipart_threshold_bytes: Option<u64>,
+
+    // for a whole upload, including all parts of a multipart one
+    #[serde(default)] // default 30
+    s3_upload_timeout_secs: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pull::MockPuller;
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn test_image() -> CachedImage {
+        CachedImage {
+            id: "abcdef".to_string(),
+            url: "https://cdn.example/images/ab/abcdef.webp".to_string(),
+            content_type: "image/webp".to_string(),
+            width: 512,
+            height: 512,
+            file_size: 1234,
+            original_file_size: Some(5678),
+            hash_algorithm: HashAlgorithm::Sha256,
+        }
+    }
+
+    async fn json_body(res: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn get_json(app: Router, uri: &str) -> serde_json::Value {
+        let res = app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        json_body(res).await
+    }
+
+    fn naming_app() -> Router {
+        Router::new()
+            .route("/image", get(|| async { image_response(test_image(), false, None, None) }))
+            .route(
+                "/stats/by-kind",
+                get(|| async { Json(HashMap::from([(ImageKind::Avatar, Stats::default())])) }),
+            )
+    }
+
+    #[test]
+    fn sharding_is_stable() {
+        let base_urls: Vec<String> = (0..3).map(|i| format!("https://cdn{}.example/", i)).collect();
+        let id = "mfrggzdfmztwq2lknnwg23tpobyxe";
+        let first = shard_base_url(&base_urls, id);
+        for _ in 0..10 {
+            assert_eq!(shard_base_url(&base_urls, id), first);
+        }
+        // only the hash matters, not which list (or which copy of it) it's looked up in
+        assert_eq!(shard_base_url(&base_urls.clone(), id), first);
+        assert_eq!(shard_base_url(&base_urls, "mzzzz"), first);
+
+        // ids are base32, between them they hit every shard
+        let used: std::collections::HashSet<&str> = "abcdefghijklmnopqrstuvwxyz234567"
+            .chars()
+            .map(|c| shard_base_url(&base_urls, &c.to_string()))
+            .collect();
+        assert_eq!(used.len(), base_urls.len());
+
+        let single = vec!["https://cdn.example/".to_string()];
+        assert_eq!(shard_base_url(&single, id), "https://cdn.example/");
+    }
+
+    #[tokio::test]
+    async fn snake_case_by_default() {
+        let body = get_json(naming_app(), "/image").await;
+        assert_eq!(body["file_size"], 1234);
+        assert!(body.get("fileSize").is_none());
+    }
+
+    #[tokio::test]
+    async fn camel_case_when_configured() {
+        let app = naming_app().layer(middleware::from_fn(camel_case_json));
+        let body = get_json(app.clone(), "/image").await;
+        assert_eq!(body["fileSize"], 1234);
+        assert_eq!(body["originalFileSize"], 5678);
+        assert!(body.get("file_size").is_none());
+
+        // keys that are data stay as they are, the structs under them don't
+        let body = get_json(app, "/stats/by-kind").await;
+        assert_eq!(body["avatar"]["totalImages"], 0);
+    }
+
+    const AVATAR_URL: &str =
+        "https://cdn.discordapp.com/attachments/123/456/avatar.png?ex=65f1a2b3&is=65df2db3&hm=0123456789abcdef";
+    const AVATAR_ATTACHMENT_ID: u64 = 456;
+
+    // the bare minimum `load_config` would need, plus whatever the test wants on top
+    fn test_config(overrides: serde_json::Value) -> Config {
+        let mut config = serde_json::json!({
+            "db": "unused, tests get their pool from sqlx::test",
+            "s3": {
+                "bucket": "test",
+                "application_id": "test",
+                "application_key": "test",
+                "endpoint": "https://s3.example",
+            },
+            "base_url": "https://cdn.example/",
+        });
+        if let (Some(config), serde_json::Value::Object(overrides)) = (config.as_object_mut(), overrides) {
+            config.extend(overrides);
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn test_png() -> Vec<u8> {
+        let image = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+        let mut out = Vec::new();
+        image
+            .write_to(&mut std::io::Cursor::new(&mut out), image::ImageFormat::Png)
+            .unwrap();
+        out
+    }
+
+    // like `real_main`'s state, but stored images go to memory and pulls go to a `MockPuller`.
+    // no background tasks, so stats are always fetched live
+    struct TestAppState {
+        state: AppState,
+        puller: Arc<MockPuller>,
+    }
+
+    impl TestAppState {
+        async fn new(pool: PgPool) -> TestAppState {
+            TestAppState::with_config(pool, serde_json::json!({})).await
+        }
+
+        async fn with_config(pool: PgPool, config: serde_json::Value) -> TestAppState {
+            db::init(&pool, None).await.unwrap();
+            let config = test_config(config);
+            let puller = Arc::new(MockPuller::default());
+            puller.add_image(AVATAR_ATTACHMENT_ID, test_png(), "image/png");
+
+            let state = AppState {
+                storer: Storer::memory(),
+                puller: puller.clone(),
+                pool,
+                stats_cache: Arc::new(RwLock::new(None)),
+                recent_pulls: Arc::new(RecentPullLog::default()),
+                in_flight: Arc::new(InFlightPulls::default()),
+                attachment_cache: Arc::new(AttachmentCache::new(attachment_cache::DEFAULT_CAPACITY)),
+                idempotency: Arc::new(IdempotencyCache::default()),
+                access_log: Arc::new(AccessLog::default()),
+                recent_errors: Arc::new(RecentErrors::new(recent_errors::DEFAULT_MAX_SIZE)),
+                // not installed globally, tests run in parallel
+                metrics: PrometheusBuilder::new().build_recorder().handle(),
+                pull_limiter: Arc::new(RateLimiter::new("pull", config.rate_limit.pull_rpm)),
+                batch_pull_limiter: Arc::new(RateLimiter::new("batch_pull", config.rate_limit.batch_pull_rpm)),
+                read_limiter: Arc::new(RateLimiter::new("read", config.rate_limit.read_rpm)),
+                shutdown: Arc::new(watch::channel(false).0),
+                config: Arc::new(config),
+            };
+            TestAppState { state, puller }
+        }
+
+        async fn request(&self, mut req: Request<Body>) -> Response {
+            // normally added by `into_make_service_with_connect_info`, the rate limiters need it
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+            router(self.state.clone())
+                .with_state(self.state.clone())
+                .oneshot(req)
+                .await
+                .unwrap()
+        }
+
+        async fn get(&self, uri: &str) -> Response {
+            self.request(Request::get(uri).body(Body::empty()).unwrap()).await
+        }
+
+        async fn pull(&self, body: serde_json::Value) -> Response {
+            let req = Request::post("/pull")
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            self.request(req).await
+        }
+    }
+
+    #[sqlx::test]
+    async fn pull_stores_the_image(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        let res = app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = json_body(res).await;
+        assert_eq!(body["new"], true);
+        assert!(body["pulled_at"].is_string());
+        assert_eq!(body["content_type"], "image/webp");
+        assert_eq!((body["width"].as_u64(), body["height"].as_u64()), (Some(64), Some(64)));
+        assert_eq!(app.puller.pull_count(), 1);
+
+        // in storage, and in the database under the attachment id
+        let url = body["url"].as_str().unwrap();
+        let path = url.strip_prefix("https://cdn.example/").unwrap();
+        let stored = app.state.storer.fetch(path).await.unwrap();
+        assert_eq!(stored.len() as u64, body["file_size"].as_u64().unwrap());
+        let row = db::get_by_attachment_id(&app.state.pool, AVATAR_ATTACHMENT_ID)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.url, url);
+    }
+
+    #[sqlx::test]
+    async fn pull_returns_the_existing_image(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        let first = json_body(app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await).await;
+
+        // the signature params change between requests for the same attachment
+        let other_signature = AVATAR_URL.replace("hm=0123456789abcdef", "hm=fedcba9876543210");
+        let res = app.pull(serde_json::json!({"url": other_signature, "kind": "avatar"})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let second = json_body(res).await;
+        assert_eq!(second["new"], false);
+        assert!(second["pulled_at"].is_null());
+        assert_eq!(second["url"], first["url"]);
+        assert_eq!(app.puller.pull_count(), 1);
+
+        // and from the database once the attachment cache doesn't have it anymore
+        app.state.attachment_cache.remove(AVATAR_ATTACHMENT_ID);
+        let third = json_body(app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await).await;
+        assert_eq!(third["url"], first["url"]);
+        assert_eq!(app.puller.pull_count(), 1);
+    }
+
+    #[sqlx::test]
+    async fn forced_pull_pulls_again(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        let first = json_body(app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await).await;
+
+        let res = app
+            .pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar", "force": true}))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let forced = json_body(res).await;
+        assert_eq!(app.puller.pull_count(), 2);
+        assert!(forced["pulled_at"].is_string());
+        // same bytes from the cdn, so the same stored image
+        assert_eq!(forced["new"], false);
+        assert_eq!(forced["url"], first["url"]);
+    }
+
+    #[sqlx::test]
+    async fn pull_rejects_non_cdn_urls(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        for url in ["https://example.com/attachments/123/456/avatar.png", "not a url"] {
+            let res = app.pull(serde_json::json!({"url": url, "kind": "avatar"})).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(json_body(res).await["error"], PKAvatarError::InvalidCdnUrl.to_string());
+        }
+        assert_eq!(app.puller.pull_count(), 0);
+    }
+
+    #[sqlx::test]
+    async fn pull_passes_on_cdn_errors(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        let res = app
+            .pull(serde_json::json!({"url": "https://cdn.discordapp.com/attachments/123/789/gone.png", "kind": "avatar"}))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(res).await["error"],
+            PKAvatarError::BadCdnResponse(reqwest::StatusCode::NOT_FOUND).to_string()
+        );
+    }
+
+    #[sqlx::test]
+    async fn batch_pull_keeps_request_order(pool: PgPool) {
+        let app = TestAppState::with_config(
+            pool,
+            serde_json::json!({"batch_download_concurrency": 2, "batch_process_concurrency": 1}),
+        )
+        .await;
+        let mut small = Vec::new();
+        image::RgbImage::from_pixel(32, 32, image::Rgb([200, 40, 40]))
+            .write_to(&mut std::io::Cursor::new(&mut small), image::ImageFormat::Png)
+            .unwrap();
+        app.puller.add_image(457, small, "image/png");
+
+        let req = Request::post("/pull/batch")
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!([
+                    {"url": AVATAR_URL, "kind": "avatar"},
+                    {"url": "https://cdn.discordapp.com/attachments/123/789/gone.png", "kind": "avatar"},
+                    {"url": "https://cdn.discordapp.com/attachments/123/457/small.png", "kind": "avatar"},
+                    {"url": "https://example.com/avatar.png", "kind": "avatar"},
+                    {"url": AVATAR_URL, "kind": "avatar"},
+                ])
+                .to_string(),
+            ))
+            .unwrap();
+        let res = app.request(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = json_body(res).await;
+        let items = body.as_array().unwrap();
+        assert_eq!(items.len(), 5);
+
+        assert_eq!(items[0]["width"], 64);
+        assert_eq!(
+            items[1]["error"],
+            PKAvatarError::BadCdnResponse(reqwest::StatusCode::NOT_FOUND).to_string()
+        );
+        assert_eq!(items[2]["width"], 32);
+        assert_eq!(items[3]["error"], PKAvatarError::InvalidCdnUrl.to_string());
+        // the same attachment twice in one batch only gets pulled once
+        assert_eq!(items[4]["url"], items[0]["url"]);
+        assert_eq!(app.puller.pull_count(), 3);
+    }
+
+    #[test]
+    fn batch_concurrency_still_accepted() {
+        let config = test_config(serde_json::json!({"batch_concurrency": 3}));
+        assert_eq!(config.batch_download_concurrency, Some(3));
+        assert_eq!(config.batch_process_concurrency, None);
+    }
+
+    #[sqlx::test]
+    async fn stats_count_stored_images(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        let res = app.get("/stats").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = json_body(res).await;
+        assert_eq!(body["total_images"], 0);
+        assert_eq!(body["by_kind"]["banner"]["total_images"], 0);
+
+        let pulled = json_body(app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await).await;
+        let body = json_body(app.get("/stats").await).await;
+        assert_eq!(body["total_images"], 1);
+        assert_eq!(body["total_file_size"], pulled["file_size"]);
+        assert_eq!(body["by_kind"]["avatar"]["total_images"], 1);
+        assert_eq!(body["dead_letter_count"], 0);
+    }
+
+    #[sqlx::test]
+    async fn health_checks_db_and_storage(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        let res = app.get("/health").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(res).await,
+            serde_json::json!({"status": "ok", "db": true, "storage": true})
+        );
+    }
+
+    #[sqlx::test]
+    async fn get_image_by_id(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        let pulled = json_body(app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await).await;
+        let row = db::get_by_attachment_id(&app.state.pool, AVATAR_ATTACHMENT_ID)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let res = app.get(&format!("/image/{}", row.id)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = json_body(res).await;
+        assert_eq!(body["id"], row.id);
+        assert_eq!(body["url"], pulled["url"]);
+        assert_eq!(body["kind"], "avatar");
+
+        let res = app.get("/image/doesnotexist").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn bearer_token_required_when_set(pool: PgPool) {
+        let app = TestAppState::with_config(pool, serde_json::json!({"bearer_token": "correct-token"})).await;
+        let get_stats = |authorization: Option<&str>| {
+            let mut req = Request::get("/stats");
+            if let Some(authorization) = authorization {
+                req = req.header(axum::http::header::AUTHORIZATION, authorization);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        for authorization in [None, Some("Bearer wrong-token"), Some("Bearer correct-toke"), Some("correct-token")] {
+            let res = app.request(get_stats(authorization)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{:?}", authorization);
+            assert_eq!(json_body(res).await["error"], PKAvatarError::Unauthorized.to_string());
+        }
+        let res = app.request(get_stats(Some("Bearer correct-token"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // covers /pull too, not just the read routes
+        let res = app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.puller.pull_count(), 0);
+
+        // monitoring doesn't have the token
+        assert_eq!(app.get("/health").await.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn no_bearer_token_needed_when_unset(pool: PgPool) {
+        let app = TestAppState::new(pool).await;
+        assert_eq!(app.get("/stats").await.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn retag_to_identical_output_changes_kind(pool: PgPool) {
+        let app = TestAppState::with_config(pool, serde_json::json!({"admin_token": "admin-token"})).await;
+        let pulled = json_body(app.pull(serde_json::json!({"url": AVATAR_URL, "kind": "avatar"})).await).await;
+        let row = db::get_by_attachment_id(&app.state.pool, AVATAR_ATTACHMENT_ID)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let req = Request::post(format!("/admin/image/{}/retag", row.id))
+            .header(axum::http::header::AUTHORIZATION, "Bearer admin-token")
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({"kind": "banner"}).to_string()))
+            .unwrap();
+        let res = app.request(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // small enough to come out the same as a banner, so it's still the same image...
+        assert_eq!(json_body(res).await["url"], pulled["url"]);
+
+        // ...but tagged with the new kind
+        let res = app.get(&format!("/image/{}", row.id)).await;
+        assert_eq!(json_body(res).await["kind"], "banner");
+    }
 }
@@ -1,5 +1,6 @@
 use std::error::Error;
 use crate::db::{ImageMeta, ImageQueueEntry};
+use crate::process::ProcessOptions;
 use crate::pull::parse_url;
 use crate::{db, process, AppState, PKAvatarError};
 use reqwest::StatusCode;
@@ -7,10 +8,16 @@ use std::sync::Arc;
 use std::time::Duration;
 use time::Instant;
 use tokio::sync::Semaphore;
-use tracing::{error, info, instrument, warn};
+use tokio::task::JoinHandle;
+use tracing::{error, info, instrument, trace, warn};
 
 static PROCESS_SEMAPHORE: Semaphore = Semaphore::const_new(100);
 
+pub const DEFAULT_MIN_IDLE_MS: u64 = 100;
+pub const DEFAULT_MAX_IDLE_MS: u64 = 30_000;
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+pub const DEFAULT_RETRY_BACKOFF_SECS: u64 = 60;
+
 pub async fn handle_item_inner(
     state: &AppState,
     item: &ImageQueueEntry,
@@ -25,7 +32,7 @@ pub async fn handle_item_inner(
         return Ok(());
     }
 
-    let pulled = state.puller.pull(&parsed).await?;
+    let pulled = state.puller.pull(&parsed, item.kind).await?;
     let data_len = pulled.data.len();
 
     let encoded = {
@@ -40,12 +47,12 @@ pub async fn handle_item_inner(
             warn!("waited more than {} ms for process semaphore", semaphore_time.whole_milliseconds());
         }
 
-        let encoded = process::process_async(pulled.data, item.kind).await?;
+        let encoded = process::process_async(pulled.data, item.kind, ProcessOptions::new(&state.config)).await?;
         drop(permit);
         encoded
     };
     let store_res = state.storer.store(&encoded).await?;
-    let final_url = format!("{}{}", state.config.base_url, store_res.path);
+    let final_url = state.url_for_path(&store_res.id, &store_res.path);
 
     db::add_image(
         &state.pool,
@@ -63,7 +70,10 @@ pub async fn handle_item_inner(
             kind: item.kind,
             uploaded_at: None,
             uploaded_by_account: None,
-            uploaded_by_system: None,
+            uploaded_by_system: item.system_id,
+            encode_quality: encoded.quality_used,
+            last_accessed_at: None,
+            hash_algorithm: encoded.hash_algorithm,
         },
     )
     .await?;
@@ -77,60 +87,123 @@ pub async fn handle_item_inner(
     Ok(())
 }
 
-pub async fn handle_item(state: &AppState) -> Result<(), PKAvatarError> {
+// returns false if the queue was empty
+pub async fn handle_item(state: &AppState) -> Result<bool, PKAvatarError> {
     // let queue_length = db::get_queue_length(&state.pool).await?;
     // info!("migrate queue length: {}", queue_length);
 
     if let Some((mut tx, item)) = db::pop_queue(&state.pool).await? {
-        match handle_item_inner(state, &item).await {
+        let res = handle_item_inner(state, &item).await;
+        // the queued url is signed, only the short form gets logged
+        let display_url = parse_url(&item.url).ok().map(|x| x.to_string());
+        if let Err(e) = &res {
+            state.recent_errors.record(e, display_url.clone(), Some(item.kind));
+        }
+
+        match res {
             Ok(_) => {
                 tx.commit().await.map_err(Into::<anyhow::Error>::into)?;
-                Ok(())
+                metrics::counter!("pkavatars_migrate_items_total", "result" => "success").increment(1);
+                Ok(true)
             }
             Err(
                 // Errors that mean the image can't be migrated and doesn't need to be retried
                 e @ (PKAvatarError::ImageDimensionsTooLarge(_, _)
+                | PKAvatarError::ImageDimensionsZero(_)
+                | PKAvatarError::LandscapeAvatarRejected(_, _)
+                | PKAvatarError::ColorSpaceConversionFailed(_)
                 | PKAvatarError::UnknownImageFormat
                 | PKAvatarError::UnsupportedImageFormat(_)
                 | PKAvatarError::UnsupportedContentType(_)
+                | PKAvatarError::SvgNotSupported
+                | PKAvatarError::CannotDetectContentType
+                | PKAvatarError::LottieNotSupported
                 | PKAvatarError::ImageFileSizeTooLarge(_, _)
+                | PKAvatarError::TooManyFrames(_, _)
                 | PKAvatarError::InvalidCdnUrl
                 | PKAvatarError::BadCdnResponse(StatusCode::NOT_FOUND | StatusCode::FORBIDDEN)),
             ) => {
-                warn!("error migrating {}, skipping: {}", item.url, e);
+                warn!(
+                    "error migrating {}, skipping: {}",
+                    display_url.as_deref().unwrap_or("[invalid url]"),
+                    e
+                );
+                db::push_failed_migration(&mut tx, &item.url, item.kind, e.code(), &e.to_string()).await?;
                 tx.commit().await.map_err(Into::<anyhow::Error>::into)?;
-                Ok(())
+                metrics::counter!("pkavatars_migrate_items_total", "result" => "skipped").increment(1);
+                Ok(true)
             },
-            Err(e @ PKAvatarError::ImageFormatError(_)) => {
-                // will add this item back to the end of the queue
-                db::push_queue(&mut *tx, &item.url, item.kind).await?;
-                tx.commit().await.map_err(Into::<anyhow::Error>::into)?;
+            // ImageFormatError included, a decoder hiccup gets a few more tries but an image that's really broken
+            // still ends up in `image_queue_dead` eventually
+            Err(e) => {
+                metrics::counter!("pkavatars_migrate_items_total", "result" => "error").increment(1);
+                let max_attempts = state.config.max_migration_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS);
+                if item.attempt_count + 1 >= max_attempts {
+                    warn!(
+                        "error migrating {}, giving up after {} attempts: {}",
+                        display_url.as_deref().unwrap_or("[invalid url]"),
+                        item.attempt_count + 1,
+                        e
+                    );
+                    db::push_dead_letter(&mut tx, &item, &e.to_string()).await?;
+                    tx.commit().await.map_err(Into::<anyhow::Error>::into)?;
+                } else {
+                    // puts the item back in the queue, then counts the attempt against it.
+                    // the wait doubles with every attempt: 1, 2, 4, 8 minutes with the defaults
+                    tx.rollback().await.map_err(Into::<anyhow::Error>::into)?;
+                    let backoff = state.config.migrate_retry_backoff_secs.unwrap_or(DEFAULT_RETRY_BACKOFF_SECS);
+                    let delay = Duration::from_secs(backoff.saturating_mul(1 << item.attempt_count.clamp(0, 16)));
+                    db::retry_later(&state.pool, item.itemid, delay).await?;
+                }
                 Err(e)
             },
-            Err(e) => Err(e),
         }
     } else {
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        Ok(())
+        Ok(false)
     }
 }
 
 #[instrument(skip(state))]
 pub async fn worker(worker_id: u32, state: Arc<AppState>) {
     info!("spawned migrate worker with id {}", worker_id);
+    let mut shutdown = state.shutdown.subscribe();
+    let min_idle = Duration::from_millis(state.config.migrate_min_idle_ms.unwrap_or(DEFAULT_MIN_IDLE_MS));
+    let max_idle = Duration::from_millis(state.config.migrate_max_idle_ms.unwrap_or(DEFAULT_MAX_IDLE_MS))
+        .max(min_idle);
+    let mut idle = min_idle;
     loop {
+        // only checked between items, an item that's already started always runs to completion
+        if *shutdown.borrow() {
+            info!("migrate worker {} shutting down", worker_id);
+            return;
+        }
+
         match handle_item(&state).await {
-            Ok(()) => {}
+            Ok(true) => idle = min_idle,
+            Ok(false) => {
+                // queue's empty, back off so idle workers aren't hammering the db
+                trace!("migrate worker {} idle, sleeping {} ms", worker_id, idle.as_millis());
+                tokio::select! {
+                    _ = tokio::time::sleep(idle) => {}
+                    _ = shutdown.changed() => {}
+                }
+                idle = (idle * 2).min(max_idle);
+            }
             Err(e) => {
                 error!("error in migrate worker {}: {}", worker_id, e.source().unwrap_or(&e));
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                #[cfg(feature = "sentry")]
+                sentry::capture_error(&e);
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    _ = shutdown.changed() => {}
+                }
             }
         }
     }
 }
 
-pub fn spawn_migrate_workers(state: Arc<AppState>, count: u32) {
-    for i in 0..count {
-        tokio::spawn(worker(i, state.clone()));
-    }
+pub fn spawn_migrate_workers(state: Arc<AppState>, count: u32) -> Vec<JoinHandle<()>> {
+    (0..count)
+        .map(|i| tokio::spawn(worker(i, state.clone())))
+        .collect()
 }
@@ -1,12 +1,103 @@
 use std::borrow::Cow;
 use std::io::Cursor;
+use std::sync::OnceLock;
 use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
 use time::Instant;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 
-use crate::{hash::Hash, ImageKind, PKAvatarError};
+use crate::hash::{Hash, HashAlgorithm};
+use crate::webp_anim::AnimEncoder;
+use crate::{Config, ImageKind, LimitsConfig, PKAvatarError};
 
-const MAX_DIMENSION: u32 = 4000;
+// default for `limits`, see `LimitsConfig`
+pub const DEFAULT_MAX_DIMENSION: u32 = 4000;
+pub const DEFAULT_WEBP_QUALITY: f32 = 90.0;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// everything needed to decode the image, anything else gets dropped
+const PNG_ESSENTIAL_CHUNKS: &[&[u8]] = &[b"IHDR", b"PLTE", b"tRNS", b"IDAT", b"IEND"];
+
+// libimagequant only does (floyd-steinberg) error diffusion, there's no ordered dithering
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case", try_from = "String")]
+pub enum QuantizeDitherMode {
+    // fastest, but gradients turn into visible bands
+    None,
+    #[default]
+    Diffusion,
+}
+
+// by hand so "ordered" gets a better error than serde's "unknown variant"
+impl TryFrom<String> for QuantizeDitherMode {
+    type Error = String;
+
+    fn try_from(mode: String) -> Result<Self, Self::Error> {
+        match mode.as_str() {
+            "none" => Ok(QuantizeDitherMode::None),
+            "diffusion" => Ok(QuantizeDitherMode::Diffusion),
+            "ordered" => Err(
+                "quantize_dither_mode \"ordered\" isn't supported, libimagequant only does error diffusion (use \"diffusion\" or \"none\")"
+                    .to_string(),
+            ),
+            other => Err(format!(
+                "unknown quantize_dither_mode \"{}\", expected \"diffusion\" or \"none\"",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProcessOptions {
+    pub quantize_before_encode: bool,
+    pub quantize_dither_mode: QuantizeDitherMode,
+    pub auto_rotate: bool,
+    pub reject_landscape_avatars: bool,
+    pub landscape_avatar_warning_only: bool,
+    pub adaptive_quality: bool,
+    // unsharp mask sigma applied after downscaling, None = don't sharpen
+    pub sharpen_sigma: Option<f32>,
+    pub normalize_color_space: bool,
+    pub embed_srgb_profile: bool,
+    pub lossless_comparison: bool,
+    // re-encode at a higher quality if the output's ssim against the input is below this, None = don't check
+    pub quality_min_score: Option<f32>,
+    // kinds that always get stored at exactly `kind.size()` (letterboxed if the aspect ratio doesn't match).
+    // animated gifs are never resized, so they're not affected
+    pub normalize_to_target_size: Vec<ImageKind>,
+    pub output_format: OutputFormat,
+    pub avif_quality: f32,
+    pub limits: LimitsConfig,
+    pub hash_algorithm: HashAlgorithm,
+}
+
+impl ProcessOptions {
+    pub fn new(config: &Config) -> ProcessOptions {
+        ProcessOptions {
+            quantize_before_encode: config.quantize_before_encode,
+            quantize_dither_mode: config.quantize_dither_mode.unwrap_or_default(),
+            auto_rotate: config.auto_rotate,
+            reject_landscape_avatars: config.reject_landscape_avatars,
+            landscape_avatar_warning_only: config.landscape_avatar_warning_only,
+            adaptive_quality: config.adaptive_quality,
+            sharpen_sigma: config
+                .sharpen_after_resize
+                .then(|| config.sharpen_sigma.unwrap_or(0.5)),
+            normalize_color_space: config.normalize_color_space,
+            embed_srgb_profile: config.embed_srgb_profile,
+            lossless_comparison: config.lossless_comparison,
+            quality_min_score: config
+                .quality_assessment
+                .then(|| config.quality_min_score.unwrap_or(0.9)),
+            normalize_to_target_size: config.normalize_to_target_size.clone(),
+            output_format: config.output_format,
+            avif_quality: config.avif_quality.unwrap_or(DEFAULT_AVIF_QUALITY),
+            limits: config.limits.clone(),
+            hash_algorithm: config.hash_algorithm,
+        }
+    }
+}
 
 pub struct ProcessOutput {
     pub width: u32,
@@ -14,46 +105,104 @@ pub struct ProcessOutput {
     pub hash: Hash,
     pub format: ProcessedFormat,
     pub data: Vec<u8>,
+    pub timings: ProcessTimings,
+    // data is the original input bytes, untouched
+    pub passthrough: bool,
+    // webp quality the output was encoded with, None if it wasn't lossy-encoded by us as a webp (passthrough, gif, avif).
+    // ends up in `encode_quality`, avif's quality scale isn't comparable so it's left out
+    pub quality_used: Option<f32>,
+    // more than one frame (animated gif/webp)
+    pub animated: bool,
+    // what `hash` was made with, for when the data changes afterwards (eg. embedding a profile)
+    pub hash_algorithm: HashAlgorithm,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct ProcessTimings {
+    pub parse_ms: u64,
+    pub decode_ms: u64,
+    pub resize_ms: u64,
+    pub encode_ms: u64,
 }
 
 #[derive(Copy, Clone, Debug)]
 pub enum ProcessedFormat {
     Webp,
-    Gif
+    // same file format, only tracked separately so we can tell which images ended up lossless
+    LosslessWebp,
+    Gif,
+    Avif,
 }
 
+// what static images get encoded to. animated ones are always webp (or gif), ravif can't do animation
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Webp,
+    Avif,
+}
+
+pub const DEFAULT_AVIF_QUALITY: f32 = 70.0;
+
 impl ProcessedFormat {
     pub fn mime_type(&self) -> &'static str {
         match self {
             ProcessedFormat::Gif => "image/gif",
-            ProcessedFormat::Webp => "image/webp"
+            ProcessedFormat::Webp | ProcessedFormat::LosslessWebp => "image/webp",
+            ProcessedFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn from_mime_type(mime_type: &str) -> Option<ProcessedFormat> {
+        match mime_type {
+            "image/gif" => Some(ProcessedFormat::Gif),
+            "image/webp" => Some(ProcessedFormat::Webp),
+            "image/avif" => Some(ProcessedFormat::Avif),
+            _ => None,
         }
     }
 
     pub fn extension(&self) -> &'static str {
         match self {
-            ProcessedFormat::Webp => "webp",
-            ProcessedFormat::Gif => "gif"
+            ProcessedFormat::Webp | ProcessedFormat::LosslessWebp => "webp",
+            ProcessedFormat::Gif => "gif",
+            ProcessedFormat::Avif => "avif",
         }
     }
 }
 
 // Moving Vec<u8> in here since the thread needs ownership of it now, it's fine, don't need it after
-pub async fn process_async(data: Vec<u8>, kind: ImageKind) -> Result<ProcessOutput, PKAvatarError> {
-    tokio::task::spawn_blocking(move || process(&data, kind)).await
+pub async fn process_async(data: Vec<u8>, kind: ImageKind, options: ProcessOptions) -> Result<ProcessOutput, PKAvatarError> {
+    tokio::task::spawn_blocking(move || process(&data, kind, &options)).await
         .map_err(|je| PKAvatarError::InternalError(je.into()))?
 }
-#[instrument(skip_all)]
-pub fn process(data: &[u8], kind: ImageKind) -> Result<ProcessOutput, PKAvatarError> {
+// output never carries the input's metadata (exif, xmp, png text chunks, ...): everything gets decoded to
+// plain pixels and re-encoded, except for webp passthrough, which has its EXIF/XMP chunks stripped.
+// the only thing kept is the color profile, as an sRGB ICCP chunk (see `embed_srgb_profile`) or a passthrough's own
+#[instrument(skip_all, fields(quality_score = tracing::field::Empty))]
+pub fn process(data: &[u8], kind: ImageKind, options: &ProcessOptions) -> Result<ProcessOutput, PKAvatarError> {
+    // nothing this short has a recognizable header, don't bother the decoders with it
+    if data.len() < 4 {
+        return Err(PKAvatarError::UnknownImageFormat);
+    }
+
+    // discord stickers can be lottie (.tgs = gzipped json), which isn't an image format at all
+    if data.starts_with(b"{") || data.starts_with(&[0x1f, 0x8b]) {
+        return Err(PKAvatarError::LottieNotSupported);
+    }
+
+    let max_dimension = options.limits.for_kind(kind).max_dimension;
     let time_before = Instant::now();
     let reader = reader_for(data);
-    match reader.format() {
+    let format = reader.format();
+    match format {
         Some(ImageFormat::Png | ImageFormat::WebP | ImageFormat::Jpeg | ImageFormat::Tiff) => {} // ok :)
         Some(ImageFormat::Gif) => {
             // animated gifs will need to be handled totally differently
             // so split off processing here and come back if it's not applicable
-            // (non-banner gifs + 1-frame animated gifs still need to be webp'd)
-            if let Some(output) = process_gif(data, kind)? {
+            // (non-banner gifs become animated webps further down, 1-frame gifs still need to be webp'd)
+            if let Some(output) = process_gif(data, kind, max_dimension, options.hash_algorithm)? {
                 return Ok(output);
             }
         },
@@ -63,10 +212,101 @@ pub fn process(data: &[u8], kind: ImageKind) -> Result<ProcessOutput, PKAvatarEr
 
     // want to check dimensions *before* decoding so we don't accidentally end up with a memory bomb
     // eg. a 16000x16000 png file is only 31kb and expands to almost a gig of memory
-    let (width, height) = assert_dimensions(reader.into_dimensions()?)?;
+    let (width, height) = assert_dimensions(reader.into_dimensions()?, max_dimension)?;
+    if width == 0 || height == 0 {
+        return Err(PKAvatarError::ImageDimensionsZero((width, height)));
+    }
+
+    // has to come from the raw bytes, the decoded image doesn't keep exif around
+    let orientation = if options.auto_rotate {
+        exif_orientation(data)
+    } else {
+        None
+    };
+
+    if kind == ImageKind::Avatar && options.reject_landscape_avatars {
+        // orientations 5-8 are rotated 90 degrees, so the stored width is the displayed height
+        let (display_width, display_height) = match orientation {
+            Some(5..=8) => (height, width),
+            _ => (width, height),
+        };
+        if display_width > display_height {
+            if options.landscape_avatar_warning_only {
+                warn!("landscape avatar ({}x{}), allowing anyway", display_width, display_height);
+            } else {
+                return Err(PKAvatarError::LandscapeAvatarRejected(display_width, display_height));
+            }
+        }
+    }
+
+    // already webp and already small enough, re-encoding would only lose quality.
+    // a rotated one still has to go through the pipeline, the exif that says so gets stripped
+    let (target_width, target_height) = kind.size();
+    let needs_normalizing = options.normalize_to_target_size.contains(&kind)
+        && (width, height) != (target_width, target_height);
+    let needs_rotating = matches!(orientation, Some(2..=8));
+    if format == Some(ImageFormat::WebP)
+        && width <= target_width
+        && height <= target_height
+        && !needs_normalizing
+        && !needs_rotating
+    {
+        // if we can't tell where the metadata is, the bytes can't be stored as-is. a re-encode drops it regardless
+        match strip_webp_metadata(data) {
+            Some(stripped) => {
+                if stripped.len() != data.len() {
+                    debug!("stripped {} bytes of webp metadata", data.len() - stripped.len());
+                }
+                let hash = Hash::new(options.hash_algorithm, &stripped);
+                info!("{}: webp passthrough, size {}K", hash, stripped.len() / 1024);
+                let animated = is_animated_webp(&stripped);
+                return Ok(ProcessOutput {
+                    data: stripped,
+                    format: ProcessedFormat::Webp,
+                    hash,
+                    width,
+                    height,
+                    timings: ProcessTimings {
+                        parse_ms: (Instant::now() - time_before).whole_milliseconds() as u64,
+                        ..Default::default()
+                    },
+                    passthrough: true,
+                    quality_used: None,
+                    animated,
+                    hash_algorithm: options.hash_algorithm,
+                });
+            }
+            None => warn!("couldn't parse webp chunks, re-encoding instead of passing through"),
+        }
+    }
+
+    // gifs and animated webps would otherwise come out as just their first frame
+    if format == Some(ImageFormat::Gif) || (format == Some(ImageFormat::WebP) && is_animated_webp(data)) {
+        if let Some(output) = process_animated(data, format, kind, options, time_before)? {
+            return Ok(output);
+        }
+    }
+
+    // text/xmp/icc/etc chunks can be a surprisingly large part of a png and the decoder doesn't need any of them.
+    // this also drops the animation chunks from apngs, leaving just the default image (the first frame)
+    let stripped = if format == Some(ImageFormat::Png) {
+        if is_apng(data) {
+            debug!("apng, only keeping the first frame");
+        }
+        strip_png(data)
+    } else {
+        None
+    };
 
     // need to make a new reader??? why can't it just use the same one. reduce duplication?
-    let reader = reader_for(data);
+    let reader = reader_for(stripped.as_deref().unwrap_or(data));
+
+    // same as exif, and `strip_png` throws away the iCCP chunk
+    let icc_profile = if options.normalize_color_space {
+        icc_profile(data, format)
+    } else {
+        None
+    };
 
     let time_after_parse = Instant::now();
 
@@ -86,21 +326,54 @@ pub fn process(data: &[u8], kind: ImageKind) -> Result<ProcessOutput, PKAvatarEr
         })?
     };
 
+    let image = match icc_profile {
+        Some(profile) => to_srgb(image, &profile)?,
+        None => image,
+    };
+
+    let image = match orientation {
+        Some(orientation) => apply_orientation(image, orientation),
+        None => image,
+    };
+
     let time_after_decode = Instant::now();
-    let image = resize(image, kind);
+    let image = resize(image, kind, options);
+    // plain rgba pixels and nothing else, whatever the decoder carried along with the image stays behind
+    let image = DynamicImage::ImageRgba8(image.into_rgba8());
     let time_after_resize = Instant::now();
 
-    let encoded = encode(image);
+    let mut encoded = match options.output_format {
+        OutputFormat::Webp => encode_with_retries(&image, options, initial_quality(data, width, height, options)),
+        OutputFormat::Avif => encode_avif(&image, options.avif_quality, options.hash_algorithm)?,
+    };
     let time_after = Instant::now();
 
+    encoded.timings = ProcessTimings {
+        parse_ms: (time_after_parse - time_before).whole_milliseconds() as u64,
+        decode_ms: (time_after_decode - time_after_parse).whole_milliseconds() as u64,
+        resize_ms: (time_after_resize - time_after_decode).whole_milliseconds() as u64,
+        encode_ms: (time_after - time_after_resize).whole_milliseconds() as u64,
+    };
+    metrics::histogram!("pkavatars_process_duration_seconds", "phase" => "decode")
+        .record((time_after_decode - time_after_parse).as_seconds_f64());
+    metrics::histogram!("pkavatars_process_duration_seconds", "phase" => "resize")
+        .record((time_after_resize - time_after_decode).as_seconds_f64());
+    metrics::histogram!("pkavatars_process_duration_seconds", "phase" => "encode")
+        .record((time_after - time_after_resize).as_seconds_f64());
+
     info!(
-        "{}: lossy size {}K (parse: {} ms, decode: {} ms, resize: {} ms, encode: {} ms)",
+        "{}: {} size {}K (parse: {} ms, decode: {} ms, resize: {} ms, encode: {} ms)",
         encoded.hash,
+        match encoded.format {
+            ProcessedFormat::LosslessWebp => "lossless",
+            ProcessedFormat::Avif => "avif",
+            _ => "lossy",
+        },
         encoded.data.len() / 1024,
-        (time_after_parse - time_before).whole_milliseconds(),
-        (time_after_decode - time_after_parse).whole_milliseconds(),
-        (time_after_resize - time_after_decode).whole_milliseconds(),
-        (time_after - time_after_resize).whole_milliseconds(),
+        encoded.timings.parse_ms,
+        encoded.timings.decode_ms,
+        encoded.timings.resize_ms,
+        encoded.timings.encode_ms,
     );
 
 
@@ -117,16 +390,28 @@ pub fn process(data: &[u8], kind: ImageKind) -> Result<ProcessOutput, PKAvatarEr
     Ok(encoded)
 }
 
-fn assert_dimensions((width, height): (u32, u32)) -> Result<(u32, u32), PKAvatarError> {
-    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+// cheap look at the header without decoding anything, for diagnostics
+pub fn probe(data: &[u8]) -> (Option<ImageFormat>, Option<(u32, u32)>) {
+    let reader = reader_for(data);
+    let format = reader.format();
+    (format, reader.into_dimensions().ok())
+}
+
+fn assert_dimensions((width, height): (u32, u32), max_dimension: u32) -> Result<(u32, u32), PKAvatarError> {
+    if width > max_dimension || height > max_dimension {
         return Err(PKAvatarError::ImageDimensionsTooLarge(
             (width, height),
-            (MAX_DIMENSION, MAX_DIMENSION),
+            (max_dimension, max_dimension),
         ));
     }
     return Ok((width, height))
 }
-fn process_gif(input_data: &[u8], kind: ImageKind) -> Result<Option<ProcessOutput>, PKAvatarError> {
+fn process_gif(
+    input_data: &[u8],
+    kind: ImageKind,
+    max_dimension: u32,
+    hash_algorithm: HashAlgorithm,
+) -> Result<Option<ProcessOutput>, PKAvatarError> {
     // gifs only supported for banners
     if kind != ImageKind::Banner {
         return Ok(None);
@@ -142,10 +427,14 @@ fn process_gif(input_data: &[u8], kind: ImageKind) -> Result<Option<ProcessOutpu
     if reader.width() as u32 > max_width || reader.height() as u32 > max_height {
         return Err(PKAvatarError::ImageDimensionsTooLarge((reader.width() as u32, reader.height() as u32), (max_width, max_height)));
     }
-    Ok(process_gif_inner(reader).map_err(Into::<anyhow::Error>::into)?)
+    Ok(process_gif_inner(reader, max_dimension, hash_algorithm).map_err(Into::<anyhow::Error>::into)?)
 }
 
-fn process_gif_inner(mut reader: gif::Decoder<Cursor<&[u8]>>) -> Result<Option<ProcessOutput>, anyhow::Error> {
+fn process_gif_inner(
+    mut reader: gif::Decoder<Cursor<&[u8]>>,
+    max_dimension: u32,
+    hash_algorithm: HashAlgorithm,
+) -> Result<Option<ProcessOutput>, anyhow::Error> {
     let time_before = Instant::now();
 
     let (width, height) = (reader.width(), reader.height());
@@ -158,7 +447,7 @@ fn process_gif_inner(mut reader: gif::Decoder<Cursor<&[u8]>>) -> Result<Option<P
     let mut frame_count = 0;
     while let Some(frame) = reader.next_frame_info()? {
         let mut frame = frame.clone();
-        assert_dimensions((frame.width as u32, frame.height as u32))?;
+        assert_dimensions((frame.width as u32, frame.height as u32), max_dimension)?;
         frame_buf.clear();
         frame_buf.resize(reader.buffer_size(), 0);
         reader.read_into_buffer(&mut frame_buf)?;
@@ -178,7 +467,7 @@ fn process_gif_inner(mut reader: gif::Decoder<Cursor<&[u8]>>) -> Result<Option<P
     let data = writer.into_inner()?;
     let time_after = Instant::now();
 
-    let hash = Hash::sha256(&data);
+    let hash = Hash::new(hash_algorithm, &data);
 
     let original_data = reader.into_inner();
     info!(
@@ -196,9 +485,439 @@ fn process_gif_inner(mut reader: gif::Decoder<Cursor<&[u8]>>) -> Result<Option<P
         hash,
         width: width as u32,
         height: height as u32,
+        // gifs don't go through the decode/resize stages, it's all one pass
+        timings: ProcessTimings {
+            encode_ms: (time_after - time_before).whole_milliseconds() as u64,
+            ..Default::default()
+        },
+        passthrough: false,
+        quality_used: None,
+        animated: true,
+        hash_algorithm,
     }))
 }
 
+// every frame gets decoded and resized, this keeps the total cpu time in check
+const MAX_ANIMATION_FRAMES: usize = 500;
+
+// animated webps get decoded at full size all at once, unlike gifs which go frame by frame
+const MAX_ANIMATION_DECODED_BYTES: u64 = 512 * 1024 * 1024;
+
+// frames get resized and handed to the encoder one at a time as they're decoded,
+// so only the frame at hand (and the encoder's own canvas) is ever kept in memory
+struct AnimationSink<'a> {
+    kind: ImageKind,
+    options: &'a ProcessOptions,
+    loop_count: i32,
+    // created with the first frame, that's when the output size is known
+    encoder: Option<AnimEncoder>,
+    frame_count: usize,
+}
+
+impl AnimationSink<'_> {
+    // `timestamp_ms` is when the frame starts showing
+    fn push(&mut self, image: DynamicImage, timestamp_ms: i32) -> Result<(), PKAvatarError> {
+        self.frame_count += 1;
+        if self.frame_count > MAX_ANIMATION_FRAMES {
+            return Err(PKAvatarError::TooManyFrames(self.frame_count, MAX_ANIMATION_FRAMES));
+        }
+
+        let image = resize(image, self.kind, self.options).into_rgba8();
+        let encoder = match &mut self.encoder {
+            Some(encoder) => encoder,
+            None => {
+                let (width, height) = image.dimensions();
+                // not adaptive: the file's size is spread over frames that haven't been counted yet,
+                // measured against one frame it always looks barely compressed
+                let encoder = AnimEncoder::new(width, height, DEFAULT_WEBP_QUALITY, self.loop_count)?;
+                self.encoder.insert(encoder)
+            }
+        };
+        encoder.add_frame(image.as_raw(), timestamp_ms)
+    }
+}
+
+// re-encodes as an animated webp, resizing every frame like a static image would be.
+// returns None if there turns out to be only one frame, that can go through the regular path
+#[instrument(skip_all)]
+fn process_animated(
+    data: &[u8],
+    format: Option<ImageFormat>,
+    kind: ImageKind,
+    options: &ProcessOptions,
+    time_before: Instant,
+) -> Result<Option<ProcessOutput>, PKAvatarError> {
+    let time_after_parse = Instant::now();
+    let mut sink = AnimationSink {
+        kind,
+        options,
+        loop_count: 0,
+        encoder: None,
+        frame_count: 0,
+    };
+    let end_timestamp_ms = if format == Some(ImageFormat::Gif) {
+        gif_frames(data, &mut sink)?
+    } else {
+        webp_frames(data, &mut sink)?
+    };
+    let frame_count = sink.frame_count;
+    let Some(encoder) = sink.encoder.filter(|_| frame_count >= 2) else {
+        return Ok(None);
+    };
+    let time_after_frames = Instant::now();
+
+    let (width, height) = encoder.dimensions();
+    let encoded = encoder.finish(end_timestamp_ms)?;
+    let time_after = Instant::now();
+
+    let hash = Hash::new(options.hash_algorithm, &encoded);
+    info!(
+        "{}: animated, size {}K, frames: {} (decode + resize + encode: {} ms, assemble: {} ms)",
+        hash,
+        encoded.len() / 1024,
+        frame_count,
+        (time_after_frames - time_after_parse).whole_milliseconds(),
+        (time_after - time_after_frames).whole_milliseconds(),
+    );
+
+    let mut output = ProcessOutput {
+        data: encoded,
+        format: ProcessedFormat::Webp,
+        hash,
+        width,
+        height,
+        // frames are decoded, resized and encoded one after another, so that's all counted as encoding
+        timings: ProcessTimings {
+            parse_ms: (time_after_parse - time_before).whole_milliseconds() as u64,
+            decode_ms: 0,
+            resize_ms: 0,
+            encode_ms: (time_after - time_after_parse).whole_milliseconds() as u64,
+        },
+        passthrough: false,
+        quality_used: Some(DEFAULT_WEBP_QUALITY),
+        animated: true,
+        hash_algorithm: options.hash_algorithm,
+    };
+    if options.embed_srgb_profile {
+        embed_srgb_profile(&mut output);
+    }
+    Ok(Some(output))
+}
+
+// returns when the last frame ends
+fn gif_frames(data: &[u8], sink: &mut AnimationSink) -> Result<i32, PKAvatarError> {
+    use image::AnimationDecoder;
+
+    let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(data))?;
+    let mut timestamp_ms = 0;
+    for frame in decoder.into_frames() {
+        // already composited onto the full canvas
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        sink.push(DynamicImage::ImageRgba8(frame.into_buffer()), timestamp_ms)?;
+
+        // browsers show anything this short at 100ms, a lot of gifs rely on that
+        let delay_ms = (numer / denom.max(1)) as i32;
+        timestamp_ms += if delay_ms <= 10 { 100 } else { delay_ms };
+    }
+    Ok(timestamp_ms)
+}
+
+// returns when the last frame ends
+fn webp_frames(data: &[u8], sink: &mut AnimationSink) -> Result<i32, PKAvatarError> {
+    let (width, height) = reader_for(data).into_dimensions()?;
+    let frame_count = count_webp_frames(data);
+    let frame_bytes = width as u64 * height as u64 * 4;
+    let max_frames = MAX_ANIMATION_FRAMES.min((MAX_ANIMATION_DECODED_BYTES / frame_bytes.max(1)) as usize);
+    if frame_count > max_frames {
+        return Err(PKAvatarError::TooManyFrames(frame_count, max_frames));
+    }
+
+    let decoded = webp::AnimDecoder::new(data)
+        .decode()
+        .map_err(|e| PKAvatarError::InternalError(anyhow::anyhow!("animated webp decode failed: {}", e)))?;
+    sink.loop_count = decoded.loop_count as i32;
+
+    // libwebp gives each frame's *end* time, the encoder wants start times
+    let mut timestamp_ms = 0;
+    for frame in &decoded {
+        sink.push((&frame).into(), timestamp_ms)?;
+        timestamp_ms = frame.get_time_ms();
+    }
+    Ok(timestamp_ms)
+}
+
+// same chunk layout as in `embed_iccp`, animated files have the animation bit set in VP8X
+fn is_animated_webp(data: &[u8]) -> bool {
+    data.get(12..16) == Some(&b"VP8X"[..]) && data.get(20).is_some_and(|flags| flags & VP8X_FLAG_ANIMATION != 0)
+}
+
+// one ANMF chunk per frame
+fn count_webp_frames(data: &[u8]) -> usize {
+    let mut count = 0;
+    let mut rest = data.get(12..).unwrap_or_default();
+    while rest.len() >= 8 {
+        let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        if &rest[..4] == b"ANMF" {
+            count += 1;
+        }
+        let Some(next) = rest.get(8 + size + size % 2..) else {
+            break;
+        };
+        rest = next;
+    }
+    count
+}
+
+// walks the chunk list directly instead of going through the png crate, since a
+// decode + re-encode would cost about as much as the decode we're trying to speed up.
+// returns None if the file doesn't look like a well-formed png, the decoder can complain about it
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = data.strip_prefix(PNG_SIGNATURE)?;
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+
+    // length (4) + type (4) + data (length) + crc (4)
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let chunk_size = length.checked_add(12)?;
+        if rest.len() < chunk_size {
+            return None;
+        }
+
+        let chunk_type = &rest[4..8];
+        if PNG_ESSENTIAL_CHUNKS.contains(&chunk_type) {
+            out.extend_from_slice(&rest[..chunk_size]);
+        }
+        rest = &rest[chunk_size..];
+
+        if chunk_type == b"IEND" {
+            break;
+        }
+    }
+
+    let stripped_bytes = (data.len() - out.len()) as u64;
+    if stripped_bytes > 0 {
+        metrics::counter!("pkavatars_png_bytes_stripped_total").increment(stripped_bytes);
+        debug!("stripped {} bytes of png metadata", stripped_bytes);
+    }
+    Some(out)
+}
+
+// apngs are regular pngs plus an `acTL` chunk before the image data
+fn is_apng(data: &[u8]) -> bool {
+    let Some(mut rest) = data.strip_prefix(PNG_SIGNATURE) else {
+        return false;
+    };
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        match &rest[4..8] {
+            b"acTL" => return true,
+            b"IDAT" | b"IEND" => return false,
+            _ => {}
+        }
+        let Some(next) = rest.get(length.saturating_add(12)..) else {
+            return false;
+        };
+        rest = next;
+    }
+    false
+}
+
+fn exif_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+fn icc_profile(data: &[u8], format: Option<ImageFormat>) -> Option<Vec<u8>> {
+    use image::ImageDecoder;
+    match format? {
+        ImageFormat::Jpeg => image::codecs::jpeg::JpegDecoder::new(Cursor::new(data))
+            .ok()?
+            .icc_profile(),
+        ImageFormat::Png => image::codecs::png::PngDecoder::new(Cursor::new(data))
+            .ok()?
+            .icc_profile(),
+        _ => None,
+    }
+}
+
+// vp8x flag bits, see https://developers.google.com/speed/webp/docs/riff_container#extended_file_format
+const VP8X_FLAG_ICC: u8 = 0x20;
+const VP8X_FLAG_ALPHA: u8 = 0x10;
+const VP8X_FLAG_EXIF: u8 = 0x08;
+const VP8X_FLAG_XMP: u8 = 0x04;
+const VP8X_FLAG_ANIMATION: u8 = 0x02;
+
+static SRGB_ICC_PROFILE: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+
+fn embed_srgb_profile(output: &mut ProcessOutput) {
+    let profile = SRGB_ICC_PROFILE.get_or_init(|| match lcms2::Profile::new_srgb().icc() {
+        Ok(profile) => Some(profile),
+        Err(e) => {
+            error!("error building srgb icc profile: {}", e);
+            None
+        }
+    });
+    let Some(profile) = profile else {
+        return;
+    };
+
+    match embed_iccp(&output.data, output.width, output.height, profile) {
+        Some(data) => {
+            output.hash = Hash::new(output.hash_algorithm, &data);
+            output.data = data;
+        }
+        None => warn!("{}: couldn't embed icc profile, leaving it untagged", output.hash),
+    }
+}
+
+// webp is a riff file: "RIFF", u32 size, "WEBP", then chunks of fourcc, u32 size, data (padded to even length).
+// simple files are just one VP8/VP8L chunk, anything else (like an ICCP chunk) needs a VP8X header in front.
+// returns None if the file doesn't look like what libwebp writes
+fn embed_iccp(data: &[u8], width: u32, height: u32, profile: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let chunks = &data[12..];
+
+    let mut out = Vec::with_capacity(data.len() + profile.len() + 32);
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+
+    let rest = match chunks.get(..4)? {
+        b"VP8X" => {
+            let size = u32::from_le_bytes(chunks.get(4..8)?.try_into().ok()?) as usize;
+            let end = 8 + size + size % 2;
+            let mut vp8x = chunks.get(..end)?.to_vec();
+            if vp8x[8] & VP8X_FLAG_ICC != 0 {
+                return None;
+            }
+            vp8x[8] |= VP8X_FLAG_ICC;
+            out.extend_from_slice(&vp8x);
+            &chunks[end..]
+        }
+        fourcc @ (b"VP8 " | b"VP8L") => {
+            // lossy can only carry alpha in a separate ALPH chunk (so it'd already be VP8X),
+            // lossless has an alpha_is_used bit in its header: 1 byte signature, 14 bits width, 14 bits height
+            let has_alpha = fourcc == b"VP8L"
+                && u32::from_le_bytes(chunks.get(9..13)?.try_into().ok()?) & (1 << 28) != 0;
+
+            out.extend_from_slice(b"VP8X");
+            out.extend_from_slice(&10u32.to_le_bytes());
+            out.push(VP8X_FLAG_ICC | if has_alpha { VP8X_FLAG_ALPHA } else { 0 });
+            out.extend_from_slice(&[0; 3]);
+            out.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            out.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            chunks
+        }
+        _ => return None,
+    };
+
+    // ICCP has to come right after VP8X
+    out.extend_from_slice(b"ICCP");
+    out.extend_from_slice(&u32::try_from(profile.len()).ok()?.to_le_bytes());
+    out.extend_from_slice(profile);
+    if profile.len() % 2 == 1 {
+        out.push(0);
+    }
+    out.extend_from_slice(rest);
+
+    let riff_size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+// passthrough webps are stored as-is otherwise, and camera/editor metadata can have gps coordinates and such in it.
+// every chunk gets looked at, the VP8X flags don't have to agree with what's actually in the file.
+// the ICCP chunk stays, the colors would be off without it. returns None if the chunks can't be parsed
+fn strip_webp_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    let mut rest = &data[12..];
+    while !rest.is_empty() {
+        let size = u32::from_le_bytes(rest.get(4..8)?.try_into().ok()?) as usize;
+        let chunk = rest.get(..8 + size)?;
+        // plenty of encoders leave out the padding byte after an odd-sized last chunk
+        let end = (8 + size + size % 2).min(rest.len());
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let flags_at = out.len() + 8;
+                out.extend_from_slice(chunk);
+                *out.get_mut(flags_at)? &= !(VP8X_FLAG_EXIF | VP8X_FLAG_XMP);
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        if size % 2 == 1 {
+            out.push(0);
+        }
+        rest = &rest[end..];
+    }
+
+    let riff_size = u32::try_from(out.len() - 8).ok()?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+// everything downstream (and every browser showing the result) assumes srgb
+#[instrument(skip_all)]
+fn to_srgb(image: DynamicImage, profile: &[u8]) -> Result<DynamicImage, PKAvatarError> {
+    let source = match lcms2::Profile::new_icc(profile) {
+        Ok(source) => source,
+        Err(e) => {
+            // garbage profile, just treat the image as srgb
+            warn!("ignoring invalid icc profile: {}", e);
+            return Ok(image);
+        }
+    };
+
+    // the decoder has already turned cmyk/grayscale into rgb(a) pixels by this point,
+    // so a non-rgb profile doesn't describe the data we have anymore
+    if source.color_space() != lcms2::ColorSpaceSignature::RgbData {
+        debug!("skipping non-rgb icc profile ({:?})", source.color_space());
+        return Ok(image);
+    }
+
+    let transform: lcms2::Transform<[u8; 4], [u8; 4]> = lcms2::Transform::new(
+        &source,
+        lcms2::PixelFormat::RGBA_8,
+        &lcms2::Profile::new_srgb(),
+        lcms2::PixelFormat::RGBA_8,
+        lcms2::Intent::Perceptual,
+    )
+    .map_err(|e| PKAvatarError::ColorSpaceConversionFailed(e.to_string()))?;
+
+    let mut image = image.into_rgba8();
+    let mut pixels: Vec<[u8; 4]> = image.pixels().map(|p| p.0).collect();
+    transform.transform_in_place(&mut pixels);
+    for (pixel, converted) in image.pixels_mut().zip(pixels) {
+        pixel.0 = converted;
+    }
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+// see the exif spec for what each of these mean, 1 is "already upright"
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
 fn reader_for(data: &[u8]) -> image::io::Reader<Cursor<&[u8]>> {
     image::io::Reader::new(Cursor::new(data))
         .with_guessed_format()
@@ -206,34 +925,140 @@ fn reader_for(data: &[u8]) -> image::io::Reader<Cursor<&[u8]>> {
 }
 
 #[instrument(skip_all)]
-fn resize(image: DynamicImage, kind: ImageKind) -> DynamicImage {
+fn resize(image: DynamicImage, kind: ImageKind, options: &ProcessOptions) -> DynamicImage {
     let (target_width, target_height) = kind.size();
-    if image.width() <= target_width && image.height() <= target_height {
+    let normalize = options.normalize_to_target_size.contains(&kind);
+    if image.width() <= target_width && image.height() <= target_height && !normalize {
         // don't resize if already smaller
         return image;
     }
 
     // todo: best filter?
+    // (scales up too, which only happens when normalizing)
     let resized = image.resize(
         target_width,
         target_height,
         image::imageops::FilterType::Lanczos3,
     );
+
+    // lanczos leaves downscaled images a bit soft
+    let resized = match options.sharpen_sigma {
+        Some(sigma) if resized.width() < image.width() || resized.height() < image.height() => {
+            resized.unsharpen(sigma, 0)
+        }
+        _ => resized,
+    };
+
+    if normalize {
+        return letterbox(resized, target_width, target_height);
+    }
     return resized;
 }
 
-#[instrument(skip_all)]
+// pads with transparency to exactly width x height, with the image centered
+fn letterbox(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    if image.width() == width && image.height() == height {
+        return image;
+    }
+
+    let mut canvas = image::RgbaImage::new(width, height);
+    image::imageops::overlay(
+        &mut canvas,
+        &image.to_rgba8(),
+        ((width - image.width()) / 2) as i64,
+        ((height - image.height()) / 2) as i64,
+    );
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// `color().has_alpha()` only tells us about the pixel format, not whether anything is see-through
+fn has_actual_transparency(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return false;
+    }
+
+    match image {
+        DynamicImage::ImageRgba8(buf) => buf.pixels().any(|p| p.0[3] != u8::MAX),
+        other => other.to_rgba8().pixels().any(|p| p.0[3] != u8::MAX),
+    }
+}
+
+// uses bytes-per-raw-byte of the input as a rough proxy for how much quality it has left.
+// barely-compressed input (eg. png) has real detail worth keeping, whereas re-encoding an
+// already heavily compressed jpeg at high quality just faithfully preserves its artifacts
+pub fn adaptive_webp_quality(original_size: u64, width: u32, height: u32) -> f32 {
+    const LOW_RATIO: f64 = 0.05;
+    const HIGH_RATIO: f64 = 0.15;
+    const LOW_QUALITY: f64 = 70.0;
+    const HIGH_QUALITY: f64 = 85.0;
+
+    let raw_size = (width as u64 * height as u64 * 4).max(1);
+    let ratio = original_size as f64 / raw_size as f64;
+    let t = ((ratio - LOW_RATIO) / (HIGH_RATIO - LOW_RATIO)).clamp(0.0, 1.0);
+    (LOW_QUALITY + (HIGH_QUALITY - LOW_QUALITY) * t) as f32
+}
+
+#[instrument(skip(image, options))]
 // can't believe this is infallible
-fn encode(image: DynamicImage) -> ProcessOutput {
+fn encode(image: &DynamicImage, options: &ProcessOptions, quality: f32) -> ProcessOutput {
     let (width, height) = (image.width(), image.height());
-    let image_buf = image.to_rgba8();
 
-    let encoded_lossy = webp::Encoder::new(&*image_buf, webp::PixelLayout::Rgba, width, height)
-        .encode_simple(false, 90.0)
-        .expect("encode should be infallible")
-        .to_vec();
+    // lots of pngs have an alpha channel that's entirely 255, don't waste bytes encoding it
+    let (image_buf, layout) = if has_actual_transparency(image) {
+        (image.to_rgba8().into_raw(), webp::PixelLayout::Rgba)
+    } else {
+        (image.to_rgb8().into_raw(), webp::PixelLayout::Rgb)
+    };
+
+    let mut encoded_lossy = encode_webp(&image_buf, layout, width, height, quality);
+
+    // helps a lot for flat-color illustrations, does nothing for photos,
+    // so just try both and keep whichever came out smaller
+    if options.quantize_before_encode {
+        match quantize(image, options.quantize_dither_mode) {
+            Ok(quantized) => {
+                let encoded_quantized =
+                    encode_webp(&quantized, webp::PixelLayout::Rgba, width, height, quality);
+                debug!(
+                    "quantized size {} bytes, unquantized size {} bytes",
+                    encoded_quantized.len(),
+                    encoded_lossy.len()
+                );
+                if encoded_quantized.len() < encoded_lossy.len() {
+                    encoded_lossy = encoded_quantized;
+                }
+            }
+            Err(e) => warn!("error quantizing image, using unquantized: {}", e),
+        }
+    }
+
+    // flat colors and pixel art can come out smaller lossless, and then it's lossless for free
+    if options.lossless_comparison {
+        let encoded_lossless = webp::Encoder::new(&image_buf, layout, width, height)
+            .encode_simple(true, 100.0)
+            .expect("encode should be infallible")
+            .to_vec();
+        if encoded_lossless.len() < encoded_lossy.len() {
+            info!(
+                "lossless smaller by {} bytes, using lossless",
+                encoded_lossy.len() - encoded_lossless.len()
+            );
+            return ProcessOutput {
+                hash: Hash::new(options.hash_algorithm, &encoded_lossless),
+                data: encoded_lossless,
+                format: ProcessedFormat::LosslessWebp,
+                width,
+                height,
+                timings: ProcessTimings::default(),
+                passthrough: false,
+                quality_used: None,
+                animated: false,
+                hash_algorithm: options.hash_algorithm,
+            };
+        }
+    }
 
-    let hash = Hash::sha256(&encoded_lossy);
+    let hash = Hash::new(options.hash_algorithm, &encoded_lossy);
 
     ProcessOutput {
         data: encoded_lossy,
@@ -241,5 +1066,443 @@ fn encode(image: DynamicImage) -> ProcessOutput {
         hash,
         width,
         height,
+        timings: ProcessTimings::default(),
+        passthrough: false,
+        quality_used: Some(quality),
+        animated: false,
+        hash_algorithm: options.hash_algorithm,
+    }
+}
+
+fn encode_with_retries(image: &DynamicImage, options: &ProcessOptions, mut quality: f32) -> ProcessOutput {
+    let mut encoded = encode(image, options, quality);
+
+    // some images just don't survive a given quality well, bump it until the output looks close enough
+    if let Some(min_score) = options.quality_min_score {
+        let reference = image.to_luma8();
+        while let Some(score) = assess_quality(&reference, &encoded) {
+            tracing::Span::current().record("quality_score", score);
+            if score >= min_score || quality >= MAX_RETRY_QUALITY {
+                break;
+            }
+            let new_quality = (quality + 10.0).min(MAX_RETRY_QUALITY);
+            info!("quality score {:.3} below {}, re-encoding at quality {} (was {})", score, min_score, new_quality, quality);
+            quality = new_quality;
+            encoded = encode(image, options, quality);
+        }
+    }
+
+    if options.embed_srgb_profile {
+        embed_srgb_profile(&mut encoded);
+    }
+    encoded
+}
+
+// avif encoding is a lot slower than webp, this is the middle of ravif's 1-10 scale
+const AVIF_SPEED: u8 = 6;
+
+// no lossless/quality retry/icc handling like for webp: `image` can't decode avif to compare against,
+// and ravif already tags the output as srgb
+fn encode_avif(image: &DynamicImage, quality: f32, hash_algorithm: HashAlgorithm) -> Result<ProcessOutput, PKAvatarError> {
+    let (width, height) = (image.width(), image.height());
+    let pixels: Vec<ravif::RGBA8> = image
+        .to_rgba8()
+        .pixels()
+        .map(|p| ravif::RGBA8::new(p.0[0], p.0[1], p.0[2], p.0[3]))
+        .collect();
+
+    let encoded = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_speed(AVIF_SPEED)
+        .encode_rgba(ravif::Img::new(pixels.as_slice(), width as usize, height as usize))
+        .map_err(|e| PKAvatarError::InternalError(anyhow::anyhow!("error encoding avif: {}", e)))?;
+
+    Ok(ProcessOutput {
+        hash: Hash::new(hash_algorithm, &encoded.avif_file),
+        data: encoded.avif_file,
+        format: ProcessedFormat::Avif,
+        width,
+        height,
+        timings: ProcessTimings::default(),
+        passthrough: false,
+        quality_used: None,
+        animated: false,
+        hash_algorithm,
+    })
+}
+
+fn initial_quality(data: &[u8], width: u32, height: u32, options: &ProcessOptions) -> f32 {
+    if options.adaptive_quality {
+        adaptive_webp_quality(data.len() as u64, width, height)
+    } else {
+        DEFAULT_WEBP_QUALITY
+    }
+}
+
+// quality retries never go past this
+const MAX_RETRY_QUALITY: f32 = 95.0;
+
+// ssim of the encoded output against what went into the encoder, None if it wasn't a lossy encode
+fn assess_quality(reference: &image::GrayImage, encoded: &ProcessOutput) -> Option<f32> {
+    encoded.quality_used?;
+    let decoded = webp::Decoder::new(&encoded.data).decode()?.to_image().to_luma8();
+    if decoded.dimensions() != reference.dimensions() {
+        return None;
+    }
+    Some(ssim(reference, &decoded))
+}
+
+// mean ssim over non-overlapping 8x8 blocks of luma. 1.0 = identical.
+// the usual gaussian-windowed version is more accurate, but this is plenty to catch really bad encodes
+fn ssim(a: &image::GrayImage, b: &image::GrayImage) -> f32 {
+    const BLOCK: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    let mut total = 0.0;
+    let mut blocks = 0;
+    for block_y in (0..height).step_by(BLOCK as usize) {
+        for block_x in (0..width).step_by(BLOCK as usize) {
+            let mut pixels = Vec::with_capacity((BLOCK * BLOCK) as usize);
+            for y in block_y..(block_y + BLOCK).min(height) {
+                for x in block_x..(block_x + BLOCK).min(width) {
+                    pixels.push((a.get_pixel(x, y).0[0] as f64, b.get_pixel(x, y).0[0] as f64));
+                }
+            }
+
+            let n = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|p| p.1).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covar) = (0.0, 0.0, 0.0);
+            for (pa, pb) in &pixels {
+                var_a += (pa - mean_a) * (pa - mean_a);
+                var_b += (pb - mean_b) * (pb - mean_b);
+                covar += (pa - mean_a) * (pb - mean_b);
+            }
+            var_a /= n;
+            var_b /= n;
+            covar /= n;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            blocks += 1;
+        }
+    }
+
+    if blocks == 0 {
+        return 1.0;
+    }
+    (total / blocks as f64) as f32
+}
+
+fn encode_webp(buf: &[u8], layout: webp::PixelLayout, width: u32, height: u32, quality: f32) -> Vec<u8> {
+    webp::Encoder::new(buf, layout, width, height)
+        .encode_simple(false, quality)
+        .expect("encode should be infallible")
+        .to_vec()
+}
+
+// quantizes to a 256-color palette and expands back out to rgba, since webp has no palette mode
+fn quantize(image: &DynamicImage, dither_mode: QuantizeDitherMode) -> Result<Vec<u8>, imagequant::Error> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let pixels: Vec<imagequant::RGBA> = image
+        .to_rgba8()
+        .pixels()
+        .map(|p| imagequant::RGBA::new(p.0[0], p.0[1], p.0[2], p.0[3]))
+        .collect();
+
+    let attr = imagequant::new();
+    let mut liq_image = attr.new_image(pixels, width, height, 0.0)?;
+    let mut res = attr.quantize(&mut liq_image)?;
+    res.set_dithering_level(match dither_mode {
+        QuantizeDitherMode::None => 0.0,
+        QuantizeDitherMode::Diffusion => 1.0,
+    })?;
+    let (palette, indices) = res.remapped(&mut liq_image)?;
+
+    Ok(indices
+        .into_iter()
+        .flat_map(|i| {
+            let c = palette[i as usize];
+            [c.r, c.g, c.b, c.a]
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16x16, red/green/blue at 100ms each, loops forever
+    const THREE_FRAME_GIF: &[u8] = include_bytes!("../tests/fixtures/three_frames.gif");
+
+    fn png(image: &DynamicImage) -> Vec<u8> {
+        let mut out = Vec::new();
+        image.write_to(&mut Cursor::new(&mut out), ImageFormat::Png).unwrap();
+        out
+    }
+
+    #[test]
+    fn animated_gif_keeps_all_frames() {
+        let output = process(THREE_FRAME_GIF, ImageKind::Avatar, &ProcessOptions::default()).unwrap();
+        assert!(output.animated);
+        assert_eq!(output.format.mime_type(), "image/webp");
+        assert_eq!((output.width, output.height), (16, 16));
+
+        let decoded = webp::AnimDecoder::new(&output.data).decode().unwrap();
+        assert_eq!(decoded.len(), 3);
+        // end times, the last frame has to keep its own 100ms too
+        let end_times: Vec<i32> = (&decoded).into_iter().map(|x| x.get_time_ms()).collect();
+        assert_eq!(end_times, vec![100, 200, 300]);
+    }
+
+    #[test]
+    fn only_actual_transparency_counts() {
+        let opaque = image::RgbaImage::from_pixel(8, 8, image::Rgba([10, 20, 30, 255]));
+        assert!(!has_actual_transparency(&DynamicImage::ImageRgba8(opaque.clone())));
+
+        let mut transparent = opaque;
+        transparent.put_pixel(3, 3, image::Rgba([10, 20, 30, 128]));
+        assert!(has_actual_transparency(&DynamicImage::ImageRgba8(transparent)));
+
+        // other formats with alpha go through the conversion
+        let la = image::GrayAlphaImage::from_pixel(8, 8, image::LumaA([50, 0]));
+        assert!(has_actual_transparency(&DynamicImage::ImageLumaA8(la)));
+        assert!(!has_actual_transparency(&DynamicImage::ImageRgb8(image::RgbImage::new(8, 8))));
+    }
+
+    #[test]
+    fn opaque_rgba_png_encodes_like_rgb() {
+        let rgb = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+        let rgba = DynamicImage::ImageRgb8(rgb.clone()).to_rgba8();
+
+        let options = ProcessOptions::default();
+        let from_rgb = process(&png(&DynamicImage::ImageRgb8(rgb)), ImageKind::Avatar, &options).unwrap();
+        let from_rgba = process(&png(&DynamicImage::ImageRgba8(rgba)), ImageKind::Avatar, &options).unwrap();
+        assert_eq!(from_rgb.data, from_rgba.data);
+    }
+
+    #[test]
+    fn adaptive_quality_follows_compression_ratio() {
+        // 100x100 is 40000 raw bytes
+        assert_eq!(adaptive_webp_quality(1_000, 100, 100), 70.0);
+        assert_eq!(adaptive_webp_quality(40_000, 100, 100), 85.0);
+        // a ratio of 0.1 is halfway along the curve
+        assert!((adaptive_webp_quality(4_000, 100, 100) - 77.5).abs() < 0.01);
+        assert!(adaptive_webp_quality(3_000, 100, 100) < adaptive_webp_quality(5_000, 100, 100));
+        // same bytes spread over more pixels = more compressed
+        assert!(adaptive_webp_quality(4_000, 200, 200) < adaptive_webp_quality(4_000, 100, 100));
+        assert_eq!(adaptive_webp_quality(0, 0, 0), 70.0);
+    }
+
+    // sum of differences between horizontal neighbours, goes up as edges get crisper
+    fn edge_contrast(image: &image::GrayImage) -> u64 {
+        image
+            .rows()
+            .flat_map(|row| {
+                let row: Vec<u8> = row.map(|p| p.0[0]).collect();
+                row.windows(2).map(|w| w[0].abs_diff(w[1]) as u64).collect::<Vec<_>>()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn sharpening_after_downscale() {
+        // 16px blocks, 4px once scaled down to avatar size. mid-tones so the sharpening doesn't just get clipped
+        let blocks = DynamicImage::ImageRgb8(image::RgbImage::from_fn(2048, 2048, |x, y| {
+            if (x / 16 + y / 16) % 2 == 0 {
+                image::Rgb([64; 3])
+            } else {
+                image::Rgb([192; 3])
+            }
+        }));
+        let plain = ProcessOptions::default();
+        let sharpened = ProcessOptions {
+            sharpen_sigma: Some(0.5),
+            ..Default::default()
+        };
+
+        let soft = resize(blocks.clone(), ImageKind::Avatar, &plain).to_luma8();
+        let sharp = resize(blocks, ImageKind::Avatar, &sharpened).to_luma8();
+        assert_eq!(sharp.dimensions(), (512, 512));
+        assert_ne!(soft, sharp);
+        // still the same picture, just crisper
+        let score = ssim(&soft, &sharp);
+        assert!(score > 0.9, "ssim {}", score);
+        assert!(edge_contrast(&sharp) > edge_contrast(&soft));
+    }
+
+    #[test]
+    fn no_sharpening_without_downscale() {
+        let small = DynamicImage::ImageRgb8(image::RgbImage::from_fn(100, 100, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, 0])
+        }));
+        let options = ProcessOptions {
+            sharpen_sigma: Some(0.5),
+            ..Default::default()
+        };
+        let resized = resize(small.clone(), ImageKind::Avatar, &options);
+        assert_eq!(resized.to_luma8(), small.to_luma8());
+        assert_eq!(ssim(&resized.to_luma8(), &small.to_luma8()), 1.0);
+    }
+
+    #[test]
+    fn dither_modes_on_flat_colors() {
+        // a handful of flat areas, the kind of image quantizing is for. every mode should get it (nearly) exact
+        let flat = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(128, 128, |x, y| match (x / 32 + y / 64) % 4 {
+            0 => image::Rgba([230, 40, 40, 255]),
+            1 => image::Rgba([40, 200, 90, 255]),
+            2 => image::Rgba([30, 60, 220, 255]),
+            _ => image::Rgba([250, 250, 250, 255]),
+        }));
+        let reference = flat.to_luma8();
+
+        for mode in [QuantizeDitherMode::None, QuantizeDitherMode::Diffusion] {
+            let quantized = quantize(&flat, mode).unwrap();
+            let quantized = DynamicImage::ImageRgba8(image::RgbaImage::from_raw(128, 128, quantized).unwrap());
+            let score = ssim(&reference, &quantized.to_luma8());
+            assert!(score > 0.98, "{:?}: ssim {}", mode, score);
+        }
+    }
+
+    #[test]
+    fn ordered_dithering_rejected() {
+        let err = serde_json::from_str::<QuantizeDitherMode>("\"ordered\"").unwrap_err();
+        assert!(err.to_string().contains("libimagequant only does error diffusion"), "{}", err);
+        assert!(matches!(
+            serde_json::from_str::<QuantizeDitherMode>("\"none\""),
+            Ok(QuantizeDitherMode::None)
+        ));
+        // still shows up in /admin/config the same way
+        assert_eq!(serde_json::to_string(&QuantizeDitherMode::Diffusion).unwrap(), "\"diffusion\"");
+    }
+
+    fn blank_png(width: u32, height: u32) -> Vec<u8> {
+        png(&DynamicImage::ImageRgb8(image::RgbImage::from_pixel(width, height, image::Rgb([90, 120, 150]))))
+    }
+
+    #[test]
+    fn landscape_avatars_rejected_when_strict() {
+        let strict = ProcessOptions {
+            reject_landscape_avatars: true,
+            ..Default::default()
+        };
+        assert!(process(&blank_png(64, 64), ImageKind::Avatar, &strict).is_ok());
+        assert!(process(&blank_png(64, 128), ImageKind::Avatar, &strict).is_ok());
+        assert!(matches!(
+            process(&blank_png(128, 64), ImageKind::Avatar, &strict),
+            Err(PKAvatarError::LandscapeAvatarRejected(128, 64))
+        ));
+        // banners are supposed to be wide
+        assert!(process(&blank_png(128, 64), ImageKind::Banner, &strict).is_ok());
+
+        let warning_only = ProcessOptions {
+            landscape_avatar_warning_only: true,
+            ..strict
+        };
+        assert!(process(&blank_png(128, 64), ImageKind::Avatar, &warning_only).is_ok());
+    }
+
+    // (fourcc, payload) of every chunk, panics if the riff structure is off
+    fn webp_chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize, data.len() - 8);
+        assert_eq!(&data[8..12], b"WEBP");
+        let mut chunks = Vec::new();
+        let mut rest = &data[12..];
+        while !rest.is_empty() {
+            let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            chunks.push((&rest[..4], &rest[8..8 + size]));
+            rest = &rest[8 + size + size % 2..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn embedded_iccp_round_trips() {
+        let profile = lcms2::Profile::new_srgb().icc().unwrap();
+        let (width, height) = (40, 30);
+        let pixels: Vec<u8> = (0..width * height).flat_map(|i| [(i % 256) as u8, 100, 200, 255]).collect();
+
+        for (layout, lossless, alpha) in [
+            (webp::PixelLayout::Rgb, false, false),
+            (webp::PixelLayout::Rgba, true, true),
+        ] {
+            let buf: Vec<u8> = match layout {
+                webp::PixelLayout::Rgb => pixels.chunks(4).flat_map(|p| [p[0], p[1], p[2]]).collect(),
+                webp::PixelLayout::Rgba => pixels.iter().enumerate().map(|(i, &x)| if i % 4 == 3 { 128 } else { x }).collect(),
+            };
+            let original = webp::Encoder::new(&buf, layout, width, height)
+                .encode_simple(lossless, 90.0)
+                .unwrap()
+                .to_vec();
+            let embedded = embed_iccp(&original, width, height, &profile).unwrap();
+
+            let chunks = webp_chunks(&embedded);
+            let (fourcc, vp8x) = chunks[0];
+            assert_eq!(fourcc, b"VP8X");
+            assert_eq!(vp8x[0] & VP8X_FLAG_ICC, VP8X_FLAG_ICC);
+            assert_eq!(vp8x[0] & VP8X_FLAG_ALPHA != 0, alpha);
+            assert_eq!(u32::from_le_bytes([vp8x[4], vp8x[5], vp8x[6], 0]) + 1, width);
+            assert_eq!(u32::from_le_bytes([vp8x[7], vp8x[8], vp8x[9], 0]) + 1, height);
+            assert_eq!(chunks[1], (&b"ICCP"[..], &profile[..]));
+
+            // the image data itself is untouched
+            let decoded_original = webp::Decoder::new(&original).decode().unwrap();
+            let decoded_embedded = webp::Decoder::new(&embedded).decode().unwrap();
+            assert_eq!(&*decoded_original, &*decoded_embedded);
+
+            // only ever one profile
+            assert!(embed_iccp(&embedded, width, height, &profile).is_none());
+        }
+    }
+
+    fn chunk(fourcc: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut out = fourcc.to_vec();
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(&body);
+        out
+    }
+
+    #[test]
+    fn strips_webp_metadata_the_flags_dont_mention() {
+        // no exif/xmp flags set, and an unpadded odd-sized last chunk
+        let vp8x = chunk(b"VP8X", &[0; 10]);
+        let data = riff(&[vp8x.clone(), chunk(b"EXIF", b"gps!"), chunk(b"XMP ", b"<x/>"), chunk(b"VP8L", b"abc")]);
+
+        let mut padded_vp8l = chunk(b"VP8L", b"abc");
+        padded_vp8l.push(0);
+        assert_eq!(strip_webp_metadata(&data), Some(riff(&[vp8x, padded_vp8l])));
+    }
+
+    #[test]
+    fn clears_webp_metadata_flags() {
+        let mut flags = [0; 10];
+        flags[0] = VP8X_FLAG_EXIF | VP8X_FLAG_ALPHA;
+        let data = riff(&[chunk(b"VP8X", &flags), chunk(b"EXIF", b"gps!"), chunk(b"VP8L", b"ab")]);
+
+        flags[0] = VP8X_FLAG_ALPHA;
+        assert_eq!(
+            strip_webp_metadata(&data),
+            Some(riff(&[chunk(b"VP8X", &flags), chunk(b"VP8L", b"ab")]))
+        );
+    }
+
+    #[test]
+    fn unparseable_webp_chunks_arent_passed_through() {
+        let mut data = riff(&[chunk(b"VP8X", &[0; 10]), chunk(b"EXIF", b"gps!")]);
+        // the exif chunk now claims to be longer than the file
+        data.truncate(data.len() - 1);
+        assert_eq!(strip_webp_metadata(&data), None);
     }
 }
@@ -1,13 +1,19 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::time::Duration;
 
-use crate::PKAvatarError;
+use crate::{Config, ImageKind, LimitsConfig, PKAvatarError};
 use anyhow::Context;
-use reqwest::{Client, ClientBuilder, StatusCode, Url};
+use futures::future::BoxFuture;
+use reqwest::{redirect, Client, ClientBuilder, StatusCode, Url};
 use time::Instant;
-use tracing::{error, instrument};
+use tracing::{debug, error, instrument, warn};
 
-const MAX_SIZE: u64 = 8 * 1024 * 1024;
+// defaults for `limits`, see `LimitsConfig`
+pub const DEFAULT_MAX_SIZE: u64 = 8 * 1024 * 1024;
+pub const DEFAULT_BANNER_MAX_SIZE: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_GIF_MAX_SIZE: u64 = 20 * 1024 * 1024;
 
 pub struct PullResult {
     pub data: Vec<u8>,
@@ -15,62 +21,193 @@ pub struct PullResult {
     pub last_modified: Option<String>,
 }
 
+// cheap to clone, `Client` shares its connection pool between clones
+#[derive(Clone)]
 pub struct Puller {
     client: Client,
+    headers_timeout: Duration,
+    body_timeout: Duration,
+    // max bytes of non-200 response bodies to log, None = don't log them
+    error_body_log_limit: Option<usize>,
+    // animated gifs are a lot bigger than anything else, but shrink a lot once converted
+    gif_max_size: u64,
+    limits: LimitsConfig,
+    // for network errors, timeouts and 429/5xx responses
+    max_retries: u32,
+    // doubles with every retry
+    initial_backoff: Duration,
+}
+
+// also caps the cdn's Retry-After, a request isn't going to wait around longer than that
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// a single failed attempt, plus how long the cdn asked us to wait before the next one
+struct PullFailure {
+    error: PKAvatarError,
+    retry_after: Option<Duration>,
+}
+
+impl From<PKAvatarError> for PullFailure {
+    fn from(error: PKAvatarError) -> Self {
+        PullFailure {
+            error,
+            retry_after: None,
+        }
+    }
 }
 
 impl Puller {
-    pub fn new() -> anyhow::Result<Puller> {
-        let client = ClientBuilder::new()
+    pub fn new(config: &Config) -> anyhow::Result<Puller> {
+        // no overall `timeout` here, it would also cover reading the body,
+        // which can legitimately take a while for big images on a slow cdn
+        let redirect_policy = if config.follow_redirects {
+            redirect::Policy::limited(config.max_redirects.unwrap_or(5))
+        } else {
+            redirect::Policy::none()
+        };
+
+        let mut builder = ClientBuilder::new()
             .connect_timeout(Duration::from_secs(3))
-            .timeout(Duration::from_secs(3))
-            .user_agent("PluralKit-Avatars/0.1")
-            .build()
-            .context("error making client")?;
-        Ok(Puller { client })
+            .redirect(redirect_policy)
+            .user_agent("PluralKit-Avatars/0.1");
+
+        // static overrides on top of the (trust-dns) resolver, eg. for pinning cdn ips or pointing at a mock cdn.
+        // the port is ignored, whatever's in the url is used
+        for (domain, ip) in config.dns_override.iter().flatten() {
+            let ip = IpAddr::from_str(ip)
+                .with_context(|| format!("invalid ip address for dns override {}: {}", domain, ip))?;
+            builder = builder.resolve(domain, SocketAddr::new(ip, 0));
+        }
+
+        let client = builder.build().context("error making client")?;
+        Ok(Puller {
+            client,
+            headers_timeout: Duration::from_secs(config.pull_headers_timeout_secs.unwrap_or(3)),
+            body_timeout: Duration::from_secs(config.pull_body_timeout_secs.unwrap_or(10)),
+            error_body_log_limit: config
+                .log_error_bodies
+                .then(|| config.log_error_body_max_bytes.unwrap_or(512)),
+            gif_max_size: config.gif_max_size_bytes.unwrap_or(DEFAULT_GIF_MAX_SIZE),
+            limits: config.limits.clone(),
+            max_retries: config.max_retries.unwrap_or(3),
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms.unwrap_or(500)),
+        })
     }
 
-    #[instrument(skip_all)]
-    pub async fn pull(&self, parsed_url: &ParsedUrl) -> Result<PullResult, PKAvatarError> {
+    pub async fn pull(&self, parsed_url: &ParsedUrl, kind: ImageKind) -> Result<PullResult, PKAvatarError> {
+        let max_bytes = self.limits.for_kind(kind).max_bytes;
+        let mut attempt = 0;
+        loop {
+            let failure = match self.pull_once(parsed_url, max_bytes).await {
+                Ok(res) => return Ok(res),
+                Err(failure) => failure,
+            };
+
+            let transient = matches!(
+                failure.error,
+                PKAvatarError::NetworkError(_)
+                    | PKAvatarError::NetworkTimeout
+                    | PKAvatarError::BadCdnResponse(
+                        StatusCode::TOO_MANY_REQUESTS
+                            | StatusCode::INTERNAL_SERVER_ERROR
+                            | StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    )
+            );
+            if !transient || attempt >= self.max_retries {
+                return Err(failure.error);
+            }
+
+            let backoff = failure
+                .retry_after
+                .unwrap_or_else(|| self.initial_backoff * 2u32.saturating_pow(attempt))
+                .min(MAX_BACKOFF);
+            attempt += 1;
+            warn!(
+                "pulling {} failed ({}), retry {}/{} in {} ms",
+                parsed_url,
+                failure.error,
+                attempt,
+                self.max_retries,
+                backoff.as_millis()
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    #[instrument(skip_all, fields(final_url = tracing::field::Empty))]
+    async fn pull_once(&self, parsed_url: &ParsedUrl, max_bytes: u64) -> Result<PullResult, PullFailure> {
         let time_before = Instant::now();
-        let mut trimmed_url = trim_url_query(&parsed_url.full_url)?;
+        let mut trimmed_url = trim_url_query(&parsed_url.full_url).map_err(PKAvatarError::from)?;
         if trimmed_url.host_str() == Some("media.discordapp.net") {
             trimmed_url.set_host(Some("cdn.discordapp.com")).expect("set_host should not fail");
         }
-        let response = self
-            .client
-            .get(trimmed_url.clone())
-            .send()
+        let response = tokio::time::timeout(self.headers_timeout, self.client.get(trimmed_url.clone()).send())
             .await
+            .map_err(|_| {
+                error!("timed out waiting for headers for {}", parsed_url);
+                PKAvatarError::NetworkTimeout
+            })?
             .map_err(|e| {
-                error!("network error for {}: {}", parsed_url.full_url, e);
+                // reqwest's errors carry the full url, signed query params and all
+                let e = e.without_url();
+                error!("network error for {}: {}", parsed_url, e);
                 PKAvatarError::NetworkError(e)
             })?;
         let time_after_headers = Instant::now();
         let status = response.status();
 
-        if status != StatusCode::OK {
-            return Err(PKAvatarError::BadCdnResponse(status));
+        if response.url() != &trimmed_url {
+            let mut final_url = response.url().clone();
+            final_url.set_query(None);
+            tracing::Span::current().record("final_url", final_url.as_str());
         }
 
-        let size = match response.content_length() {
-            None => return Err(PKAvatarError::MissingHeader("Content-Length")),
-            Some(size) if size > MAX_SIZE => {
-                return Err(PKAvatarError::ImageFileSizeTooLarge(size, MAX_SIZE))
+        // only ends up here if following redirects is turned off (or we ran out of them)
+        if status.is_redirection() {
+            return Err(PKAvatarError::BadCdnResponse(StatusCode::MOVED_PERMANENTLY).into());
+        }
+
+        if status != StatusCode::OK {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.parse::<u64>().ok()) // http dates aren't worth supporting here
+                .map(Duration::from_secs);
+            if let Some(max_bytes) = self.error_body_log_limit {
+                let body = tokio::time::timeout(self.body_timeout, read_error_body(response, max_bytes))
+                    .await
+                    .unwrap_or_else(|_| "[timed out reading body]".to_string());
+                error!("{} responded {}: {}", parsed_url, status, body);
             }
-            Some(size) => size,
-        };
+            return Err(PullFailure {
+                error: PKAvatarError::BadCdnResponse(status),
+                retry_after,
+            });
+        }
 
-        let content_type = response
+        // some cdn setups leave the header off, then the type gets sniffed from the body once it's read
+        let header_mime = response
             .headers()
             .get(reqwest::header::CONTENT_TYPE)
             .and_then(|x| x.to_str().ok()) // invalid (non-unicode) header = missing, why not
             .map(|mime| mime.split(';').next().unwrap_or("")) // cut off at ;
-            .ok_or(PKAvatarError::MissingHeader("Content-Type"))?
-            .to_owned();
-        let mime = match content_type.as_str() {
-            mime @ ("image/jpeg" | "image/png" | "image/gif" | "image/webp" | "image/tiff") => mime,
-            _ => return Err(PKAvatarError::UnsupportedContentType(content_type)),
+            .map(supported_mime)
+            .transpose()?;
+
+        // don't know yet if it's a gif without the header, so allow the bigger of the two for now
+        let max_size = match header_mime {
+            Some(mime) => max_size_for(mime, self.gif_max_size, max_bytes),
+            None => max_bytes.max(self.gif_max_size),
+        };
+        let size = match response.content_length() {
+            None => return Err(PKAvatarError::MissingHeader("Content-Length").into()),
+            Some(size) if size > max_size => {
+                return Err(PKAvatarError::ImageFileSizeTooLarge(size, max_size).into())
+            }
+            Some(size) => size,
         };
 
         let last_modified = response
@@ -79,26 +216,52 @@ impl Puller {
             .and_then(|x| x.to_str().ok())
             .map(|x| x.to_string());
 
-        let body = response.bytes().await.map_err(|e| {
-            error!("network error for {}: {}", parsed_url.full_url, e);
-            PKAvatarError::NetworkError(e)
-        })?;
+        let body = tokio::time::timeout(self.body_timeout, response.bytes())
+            .await
+            .map_err(|_| {
+                error!("timed out reading body for {}", parsed_url);
+                PKAvatarError::NetworkTimeout
+            })?
+            .map_err(|e| {
+                // reqwest's errors carry the full url, signed query params and all
+                let e = e.without_url();
+                error!("network error for {}: {}", parsed_url, e);
+                PKAvatarError::NetworkError(e)
+            })?;
         if body.len() != size as usize {
             // ???does this ever happen?
             return Err(PKAvatarError::InternalError(anyhow::anyhow!(
                 "server responded with wrong length"
-            )));
+            ))
+            .into());
         }
         let time_after_body = Instant::now();
 
+        let mime = match header_mime {
+            Some(mime) => mime,
+            None => {
+                // every supported format's magic bytes fit in the first 16
+                let sniffed = infer::get(&body[..body.len().min(16)])
+                    .ok_or(PKAvatarError::CannotDetectContentType)?;
+                debug!("no content-type header, sniffed {}", sniffed.mime_type());
+                let mime = supported_mime(sniffed.mime_type())?;
+                let max_size = max_size_for(mime, self.gif_max_size, max_bytes);
+                if size > max_size {
+                    return Err(PKAvatarError::ImageFileSizeTooLarge(size, max_size).into());
+                }
+                mime
+            }
+        };
+
         let headers_time = time_after_headers - time_before;
         let body_time = time_after_body - time_after_headers;
+        metrics::histogram!("pkavatars_pull_duration_seconds").record((time_after_body - time_before).as_seconds_f64());
 
         // can't do dynamic log level lmao
         if status != StatusCode::OK {
-            tracing::warn!("{}: {} (headers: {}ms, body: {}ms)", status, &trimmed_url, headers_time.whole_milliseconds(), body_time.whole_milliseconds());
+            tracing::warn!("{}: {} (headers: {}ms, body: {}ms)", status, parsed_url, headers_time.whole_milliseconds(), body_time.whole_milliseconds());
         } else {
-            tracing::info!("{}: {} (headers: {}ms, body: {}ms)", status, &trimmed_url, headers_time.whole_milliseconds(), body_time.whole_milliseconds());
+            tracing::info!("{}: {} (headers: {}ms, body: {}ms)", status, parsed_url, headers_time.whole_milliseconds(), body_time.whole_milliseconds());
         };
 
         Ok(PullResult {
@@ -109,6 +272,103 @@ impl Puller {
     }
 }
 
+pub fn supported_mime(content_type: &str) -> Result<&'static str, PKAvatarError> {
+    match content_type {
+        "image/jpeg" => Ok("image/jpeg"),
+        "image/png" => Ok("image/png"),
+        "image/gif" => Ok("image/gif"),
+        "image/webp" => Ok("image/webp"),
+        "image/tiff" => Ok("image/tiff"),
+        "image/svg+xml" => Err(PKAvatarError::SvgNotSupported),
+        other => Err(PKAvatarError::UnsupportedContentType(other.to_string())),
+    }
+}
+
+pub fn max_size_for(mime: &str, gif_max_size: u64, max_size: u64) -> u64 {
+    if mime == "image/gif" { gif_max_size } else { max_size }
+}
+
+// so handlers can be pointed at something other than the real cdn
+pub trait ImagePuller: Send + Sync {
+    fn pull<'a>(&'a self, parsed_url: &'a ParsedUrl, kind: ImageKind) -> BoxFuture<'a, Result<PullResult, PKAvatarError>>;
+}
+
+impl ImagePuller for Puller {
+    fn pull<'a>(&'a self, parsed_url: &'a ParsedUrl, kind: ImageKind) -> BoxFuture<'a, Result<PullResult, PKAvatarError>> {
+        Box::pin(Puller::pull(self, parsed_url, kind))
+    }
+}
+
+// stands in for the cdn in tests. attachments that weren't added 404, like they would on the real thing
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockPuller {
+    responses: std::sync::Mutex<std::collections::HashMap<u64, MockResponse>>,
+    pulls: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+enum MockResponse {
+    Image(Vec<u8>, String),
+    Error(fn() -> PKAvatarError),
+}
+
+#[cfg(test)]
+impl MockPuller {
+    pub fn add_image(&self, attachment_id: u64, data: Vec<u8>, content_type: &str) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(attachment_id, MockResponse::Image(data, content_type.to_string()));
+    }
+
+    pub fn add_error(&self, attachment_id: u64, error: fn() -> PKAvatarError) {
+        self.responses.lock().unwrap().insert(attachment_id, MockResponse::Error(error));
+    }
+
+    // how many times `pull` was called, to tell cache hits from actual pulls
+    pub fn pull_count(&self) -> usize {
+        self.pulls.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl ImagePuller for MockPuller {
+    fn pull<'a>(&'a self, parsed_url: &'a ParsedUrl, _kind: ImageKind) -> BoxFuture<'a, Result<PullResult, PKAvatarError>> {
+        self.pulls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let res = match self.responses.lock().unwrap().get(&parsed_url.attachment_id) {
+            Some(MockResponse::Image(data, content_type)) => Ok(PullResult {
+                data: data.clone(),
+                content_type: content_type.clone(),
+                last_modified: None,
+            }),
+            Some(MockResponse::Error(error)) => Err(error()),
+            None => Err(PKAvatarError::BadCdnResponse(StatusCode::NOT_FOUND)),
+        };
+        Box::pin(async move { res })
+    }
+}
+
+// reads at most `max_bytes` of the body, for logging
+async fn read_error_body(mut response: reqwest::Response, max_bytes: usize) -> String {
+    let mut buf = Vec::new();
+    while buf.len() < max_bytes {
+        match response.chunk().await {
+            Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(_) => break,
+        }
+    }
+    buf.truncate(max_bytes);
+
+    match std::str::from_utf8(&buf) {
+        Ok(text) => text.to_string(),
+        // cut off in the middle of a character by the truncation, keep what's valid
+        Err(e) if e.error_len().is_none() => String::from_utf8_lossy(&buf[..e.valid_up_to()]).into_owned(),
+        Err(_) => "[non-UTF-8 body]".to_string(),
+    }
+}
+
 #[derive(Debug)]
 pub struct ParsedUrl {
     pub channel_id: u64,
@@ -117,6 +377,13 @@ pub struct ParsedUrl {
     pub full_url: String,
 }
 
+// for logs, the full url carries the signed expiry params
+impl fmt::Display for ParsedUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "attachment/{}/{}", self.attachment_id, self.filename)
+    }
+}
+
 pub fn parse_url(url: &str) -> anyhow::Result<ParsedUrl> {
     // todo: should this return PKAvatarError::InvalidCdnUrl?
     let url = Url::from_str(url).context("invalid url")?;
@@ -161,4 +428,93 @@ fn trim_url_query(url: &str) -> anyhow::Result<Url> {
     parsed.set_query(if new_query.len() > 0 { Some(&new_query) } else { None });
 
     Ok(parsed)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNED_URL: &str = "https://cdn.discordapp.com/attachments/123/456/avatar.png?ex=65f1a2b3&is=65df2db3&hm=0123456789abcdef&size=4096";
+
+    #[test]
+    fn display_leaves_out_the_query() {
+        let parsed = parse_url(SIGNED_URL).unwrap();
+        let display = parsed.to_string();
+        assert_eq!(display, "attachment/456/avatar.png");
+        for param in ["?", "ex=", "is=", "hm=", "0123456789abcdef"] {
+            assert!(!display.contains(param), "{}", param);
+        }
+        // still there for the actual request
+        assert!(parsed.full_url.contains("hm=0123456789abcdef"));
+    }
+
+    #[tokio::test]
+    async fn mock_puller_answers_like_the_cdn() {
+        let mock = std::sync::Arc::new(MockPuller::default());
+        mock.add_image(456, b"not really a png".to_vec(), "image/png");
+        mock.add_error(789, || PKAvatarError::BadCdnResponse(StatusCode::FORBIDDEN));
+        // what AppState holds
+        let puller: std::sync::Arc<dyn ImagePuller> = mock.clone();
+
+        let res = puller.pull(&parse_url(SIGNED_URL).unwrap(), ImageKind::Avatar).await.unwrap();
+        assert_eq!(res.data, b"not really a png");
+        assert_eq!(res.content_type, "image/png");
+
+        let forbidden = parse_url("https://cdn.discordapp.com/attachments/123/789/a.png").unwrap();
+        assert!(matches!(
+            puller.pull(&forbidden, ImageKind::Avatar).await,
+            Err(PKAvatarError::BadCdnResponse(StatusCode::FORBIDDEN))
+        ));
+        let missing = parse_url("https://cdn.discordapp.com/attachments/123/1/a.png").unwrap();
+        assert!(matches!(
+            puller.pull(&missing, ImageKind::Avatar).await,
+            Err(PKAvatarError::BadCdnResponse(StatusCode::NOT_FOUND))
+        ));
+        assert_eq!(mock.pull_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_retried() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            // first connection never gets an answer, the second one gets the image
+            let (_hung, _) = listener.accept().await.unwrap();
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = conn.read(&mut buf).await.unwrap();
+            let body = b"not really a png";
+            let head = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: image/png\r\ncontent-length: {}\r\n\r\n",
+                body.len()
+            );
+            conn.write_all(head.as_bytes()).await.unwrap();
+            conn.write_all(body).await.unwrap();
+        });
+
+        let puller = Puller {
+            client: Client::new(),
+            headers_timeout: Duration::from_millis(200),
+            body_timeout: Duration::from_secs(1),
+            error_body_log_limit: None,
+            gif_max_size: DEFAULT_GIF_MAX_SIZE,
+            limits: LimitsConfig::default(),
+            max_retries: 1,
+            initial_backoff: Duration::from_millis(1),
+        };
+        let parsed_url = ParsedUrl {
+            channel_id: 123,
+            attachment_id: 456,
+            filename: "avatar.png".to_string(),
+            full_url: format!("http://127.0.0.1:{}/attachments/123/456/avatar.png", port),
+        };
+        let res = puller.pull(&parsed_url, ImageKind::Avatar).await.unwrap();
+        assert_eq!(res.data, b"not really a png");
+    }
+
+    #[test]
+    fn trimming_keeps_only_the_signature() {
+        let trimmed = trim_url_query(SIGNED_URL).unwrap();
+        assert_eq!(trimmed.query(), Some("ex=65f1a2b3&is=65df2db3&hm=0123456789abcdef"));
+    }
+}
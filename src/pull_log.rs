@@ -0,0 +1,53 @@
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+// pulls for the same attachment closer together than this are considered duplicates
+pub const DUPLICATE_WINDOW: Duration = Duration::from_secs(60);
+
+// tracks recently pulled attachment ids, to find clients that keep re-requesting the same image
+#[derive(Default)]
+pub struct RecentPullLog {
+    // attachment id -> (last seen, hits within the window)
+    entries: DashMap<u64, (Instant, u32)>,
+}
+
+impl RecentPullLog {
+    // returns the hit count if this attachment was already pulled within the window
+    pub fn record(&self, attachment_id: u64) -> Option<u32> {
+        let now = Instant::now();
+        let mut entry = self.entries.entry(attachment_id).or_insert((now, 0));
+        let (last_seen, count) = *entry;
+
+        if count > 0 && now - last_seen < DUPLICATE_WINDOW {
+            *entry = (now, count + 1);
+            Some(count + 1)
+        } else {
+            *entry = (now, 1);
+            None
+        }
+    }
+
+    pub fn evict_stale(&self) {
+        self.entries
+            .retain(|_, (last_seen, _)| last_seen.elapsed() < DUPLICATE_WINDOW);
+    }
+
+    pub fn hottest(&self, limit: usize) -> Vec<(u64, u32)> {
+        let mut entries: Vec<(u64, u32)> = self
+            .entries
+            .iter()
+            .filter(|x| x.value().0.elapsed() < DUPLICATE_WINDOW)
+            .map(|x| (*x.key(), x.value().1))
+            .collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1));
+        entries.truncate(limit);
+        entries
+    }
+}
+
+pub async fn cleanup_task(log: std::sync::Arc<RecentPullLog>) {
+    loop {
+        tokio::time::sleep(DUPLICATE_WINDOW).await;
+        log.evict_stale();
+    }
+}
@@ -0,0 +1,87 @@
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::PKAvatarError;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// fixed one-minute windows per client ip. one of these per route group,
+// since a pull costs a lot more than a metadata lookup
+pub struct RateLimiter {
+    name: &'static str,
+    // 0 = unlimited
+    rpm: u32,
+    // ip -> (window start, requests in window)
+    entries: DashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    pub fn new(name: &'static str, rpm: u32) -> RateLimiter {
+        RateLimiter {
+            name,
+            rpm,
+            entries: DashMap::new(),
+        }
+    }
+
+    // returns how long until the client can try again if it's over the limit
+    pub fn check(&self, ip: IpAddr) -> Option<Duration> {
+        if self.rpm == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut entry = self.entries.entry(ip).or_insert((now, 0));
+        let (window_start, count) = *entry;
+
+        if now - window_start >= WINDOW {
+            *entry = (now, 1);
+            None
+        } else if count >= self.rpm {
+            Some(WINDOW - (now - window_start))
+        } else {
+            *entry = (window_start, count + 1);
+            None
+        }
+    }
+
+    pub fn evict_stale(&self) {
+        self.entries
+            .retain(|_, (window_start, _)| window_start.elapsed() < WINDOW);
+    }
+}
+
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(retry_after) = limiter.check(addr.ip()) else {
+        return next.run(req).await;
+    };
+
+    let mut res = PKAvatarError::RateLimited(limiter.name).into_response();
+    // round up, retrying a bit early would just get limited again
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    res.headers_mut().insert("retry-after", HeaderValue::from(secs));
+    // retry-after can only hold a delay, so the limit that was hit goes in its own header
+    res.headers_mut()
+        .insert("x-ratelimit-scope", HeaderValue::from_static(limiter.name));
+    res
+}
+
+pub async fn cleanup_task(limiters: Vec<Arc<RateLimiter>>) {
+    loop {
+        tokio::time::sleep(WINDOW).await;
+        for limiter in &limiters {
+            limiter.evict_stale();
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use crate::{ImageKind, PKAvatarError};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+pub const DEFAULT_MAX_SIZE: usize = 100;
+
+#[derive(Serialize, Clone)]
+pub struct ErrorRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub error_code: &'static str,
+    pub error_message: String,
+    // short form (see `ParsedUrl`'s Display), the full one is signed
+    pub url: Option<String>,
+    pub kind: Option<ImageKind>,
+}
+
+// last few errors from pulls and the migrate workers, so they can be looked at without digging through logs.
+// only kept in memory, they're gone after a restart
+pub struct RecentErrors {
+    max_size: usize,
+    entries: Mutex<VecDeque<ErrorRecord>>,
+}
+
+impl RecentErrors {
+    pub fn new(max_size: usize) -> RecentErrors {
+        RecentErrors {
+            max_size,
+            entries: Mutex::new(VecDeque::with_capacity(max_size)),
+        }
+    }
+
+    pub fn record(&self, error: &PKAvatarError, url: Option<String>, kind: Option<ImageKind>) {
+        if self.max_size == 0 {
+            return;
+        }
+
+        let record = ErrorRecord {
+            timestamp: OffsetDateTime::now_utc(),
+            error_code: error.code(),
+            error_message: error.to_string(),
+            url,
+            kind,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_size {
+            entries.pop_front();
+        }
+        entries.push_back(record);
+    }
+
+    // newest first
+    pub fn latest(&self, limit: usize) -> Vec<ErrorRecord> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().rev().take(limit).cloned().collect()
+    }
+}
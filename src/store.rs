@@ -1,9 +1,67 @@
-use crate::process::ProcessOutput;
+use crate::b2::B2Backend;
+use crate::process::{ProcessOutput, ProcessedFormat};
 use crate::Config;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
 use tracing::error;
+use uuid::Uuid;
 
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    // any s3-compatible api (what b2 has been used through so far)
+    #[default]
+    S3,
+    // backblaze b2's native api
+    B2,
+}
+
+#[derive(Clone)]
+enum Backend {
+    S3(s3::Bucket),
+    B2(Arc<B2Backend>),
+    // path -> (data, content type), so tests don't need a bucket
+    #[cfg(test)]
+    Memory(Arc<DashMap<String, (Vec<u8>, String)>>),
+}
+
+#[derive(Clone)]
 pub struct Storer {
-    bucket: s3::Bucket,
+    backend: Backend,
+
+    // rust-s3 builds a fresh http client for every request, so there's no shared
+    // connection pool we could size - limit concurrent uploads ourselves instead
+    upload_semaphore: Option<Arc<Semaphore>>,
+
+    // 0 = never use multipart
+    multipart_threshold: u64,
+
+    upload_timeout: Duration,
+
+    // multipart uploads currently in progress, by a random id
+    uploads: Arc<DashMap<Uuid, (String, UploadProgress)>>,
+}
+
+pub const DEFAULT_UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+// s3 requires every part except the last to be at least 5MB
+const MULTIPART_CHUNK_SIZE: usize = 5 * 1024 * 1024;
+
+#[derive(Serialize)]
+pub struct S3ObjectInfo {
+    pub s3_exists: bool,
+    pub s3_size: Option<i64>,
+    pub s3_content_type: Option<String>,
+    pub s3_last_modified: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct UploadProgress {
+    pub bytes_sent: u64,
+    pub total_bytes: u64,
 }
 
 pub struct StoreResult {
@@ -13,6 +71,44 @@ pub struct StoreResult {
 
 impl Storer {
     pub fn new(config: &Config) -> anyhow::Result<Storer> {
+        let upload_timeout = config
+            .s3
+            .s3_upload_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_UPLOAD_TIMEOUT);
+
+        let backend = match config.storage_backend {
+            StorageBackend::S3 => Backend::S3(Storer::make_bucket(config, upload_timeout)?),
+            StorageBackend::B2 => Backend::B2(Arc::new(B2Backend::new(config)?)),
+        };
+
+        let upload_semaphore = config
+            .s3
+            .s3_max_connections
+            // a semaphore with no permits would hang every upload forever
+            .map(|x| Arc::new(Semaphore::new(x.max(1))));
+
+        Ok(Storer {
+            backend,
+            upload_semaphore,
+            multipart_threshold: config.s3.s3_multipart_threshold_bytes.unwrap_or(0),
+            upload_timeout,
+            uploads: Arc::new(DashMap::new()),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn memory() -> Storer {
+        Storer {
+            backend: Backend::Memory(Arc::new(DashMap::new())),
+            upload_semaphore: None,
+            multipart_threshold: 0,
+            upload_timeout: DEFAULT_UPLOAD_TIMEOUT,
+            uploads: Arc::new(DashMap::new()),
+        }
+    }
+
+    fn make_bucket(config: &Config, request_timeout: Duration) -> anyhow::Result<s3::Bucket> {
         let region = s3::Region::Custom {
             region: "s3".to_string(),
             endpoint: config.s3.endpoint.to_string(),
@@ -27,17 +123,145 @@ impl Storer {
         )
         .unwrap();
 
-        let bucket = s3::Bucket::new(&config.s3.bucket, region, credentials)?;
+        // rust-s3 doesn't take a client of our own, only a timeout for the ones it builds (default 60s)
+        let mut bucket = s3::Bucket::new(&config.s3.bucket, region, credentials)?;
+        bucket.set_request_timeout(Some(request_timeout));
+        Ok(bucket)
+    }
+
+    // where an image with this id (hash) lives in the bucket
+    pub fn path_for_id(id: &str, format: ProcessedFormat) -> String {
+        format!("images/{}/{}.{}", &id[..2], &id[2..], format.extension())
+    }
+
+    pub async fn check_storage(&self) -> anyhow::Result<()> {
+        let bucket = match &self.backend {
+            Backend::S3(bucket) => bucket,
+            Backend::B2(b2) => return b2.check().await,
+            #[cfg(test)]
+            Backend::Memory(_) => return Ok(()),
+        };
+
+        // cheapest request that actually touches the bucket
+        let (_, status_code) = bucket
+            .list_page("".to_string(), None, None, None, Some(1))
+            .await?;
+        if status_code != 200 {
+            anyhow::bail!("storage backend responded status code {}", status_code);
+        }
+        Ok(())
+    }
+
+    pub async fn get_object_info(&self, path: &str) -> anyhow::Result<S3ObjectInfo> {
+        let bucket = match &self.backend {
+            Backend::S3(bucket) => bucket,
+            Backend::B2(b2) => return b2.get_object_info(path).await,
+            #[cfg(test)]
+            Backend::Memory(objects) => {
+                let object = objects.get(path);
+                return Ok(S3ObjectInfo {
+                    s3_exists: object.is_some(),
+                    s3_size: object.as_ref().map(|x| x.0.len() as i64),
+                    s3_content_type: object.as_ref().map(|x| x.1.clone()),
+                    s3_last_modified: None,
+                });
+            }
+        };
+
+        let (head, status_code) = bucket.head_object(path).await?;
+        match status_code {
+            200 => Ok(S3ObjectInfo {
+                s3_exists: true,
+                s3_size: head.content_length,
+                s3_content_type: head.content_type,
+                s3_last_modified: head.last_modified,
+            }),
+            404 => Ok(S3ObjectInfo {
+                s3_exists: false,
+                s3_size: None,
+                s3_content_type: None,
+                s3_last_modified: None,
+            }),
+            _ => anyhow::bail!("storage backend responded status code {}", status_code),
+        }
+    }
+
+    pub async fn fetch(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let bucket = match &self.backend {
+            Backend::S3(bucket) => bucket,
+            Backend::B2(b2) => return b2.fetch(path).await,
+            #[cfg(test)]
+            Backend::Memory(objects) => {
+                return match objects.get(path) {
+                    Some(object) => Ok(object.0.clone()),
+                    None => anyhow::bail!("storage backend responded status code 404"),
+                };
+            }
+        };
 
-        Ok(Storer { bucket })
+        let res = bucket.get_object(path).await?;
+        if res.status_code() != 200 {
+            anyhow::bail!("storage backend responded status code {}", res.status_code());
+        }
+        Ok(res.bytes().to_vec())
     }
 
     pub async fn store(&self, res: &ProcessOutput) -> anyhow::Result<StoreResult> {
+        let _permit = match &self.upload_semaphore {
+            Some(semaphore) => Some(semaphore.acquire().await?),
+            None => None,
+        };
+
+        // waiting for a permit doesn't count, only the upload itself.
+        // dropping a multipart upload midway aborts it (see `AbortUploadOnDrop`)
+        tokio::time::timeout(self.upload_timeout, self.store_inner(res))
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "timed out uploading {} after {} s",
+                    res.hash,
+                    self.upload_timeout.as_secs()
+                )
+            })?
+    }
+
+    async fn store_inner(&self, res: &ProcessOutput) -> anyhow::Result<StoreResult> {
         // errors here are all going to be internal
         let encoded_hash = res.hash.to_string();
-        let path = format!("images/{}/{}.{}", &encoded_hash[..2], &encoded_hash[2..], res.format.extension());
-        let res = self
-            .bucket
+        let path = Storer::path_for_id(&encoded_hash, res.format);
+
+        let bucket = match &self.backend {
+            Backend::S3(bucket) => bucket,
+            Backend::B2(b2) => {
+                // b2's large file api only kicks in at 5GB+, not worth bothering with for images
+                b2.upload(&path, &res.data, res.format.mime_type()).await?;
+                tracing::debug!("uploaded image to {} (b2)", &path);
+                return Ok(StoreResult {
+                    id: encoded_hash,
+                    path,
+                });
+            }
+            #[cfg(test)]
+            Backend::Memory(objects) => {
+                objects.insert(path.clone(), (res.data.clone(), res.format.mime_type().to_string()));
+                return Ok(StoreResult {
+                    id: encoded_hash,
+                    path,
+                });
+            }
+        };
+
+        if self.multipart_threshold > 0 && res.data.len() as u64 > self.multipart_threshold {
+            self.store_multipart(bucket, &path, &res.data, res.format.mime_type())
+                .await?;
+            tracing::debug!("uploaded image to {} (multipart)", &path);
+            return Ok(StoreResult {
+                id: encoded_hash,
+                path,
+            });
+        }
+
+        let res = bucket
             .put_object_with_content_type(&path, &res.data, res.format.mime_type())
             .await?;
         if res.status_code() != 200 {
@@ -54,4 +278,112 @@ impl Storer {
             path,
         })
     }
+
+    async fn store_multipart(&self, bucket: &s3::Bucket, path: &str, data: &[u8], content_type: &str) -> anyhow::Result<()> {
+        let upload = bucket
+            .initiate_multipart_upload(path, content_type)
+            .await?;
+
+        // don't leave half-uploaded parts lying around (they still cost storage),
+        // whether we error out or the whole request gets cancelled halfway
+        let mut abort_guard = AbortUploadOnDrop {
+            bucket: bucket.clone(),
+            path: path.to_string(),
+            upload_id: Some(upload.upload_id.clone()),
+        };
+
+        let total_bytes = data.len() as u64;
+        let progress = self.track_upload(path, total_bytes);
+
+        let mut parts = Vec::new();
+        let mut bytes_sent = 0;
+        for (i, chunk) in data.chunks(MULTIPART_CHUNK_SIZE).enumerate() {
+            let part = bucket
+                .put_multipart_chunk(chunk.to_vec(), path, (i + 1) as u32, &upload.upload_id, content_type)
+                .await?;
+            parts.push(part);
+
+            bytes_sent += chunk.len() as u64;
+            // only fails if the tracking task is gone, which doesn't matter to the upload itself
+            let _ = progress
+                .send(UploadProgress {
+                    bytes_sent,
+                    total_bytes,
+                })
+                .await;
+        }
+
+        let res = bucket
+            .complete_multipart_upload(path, &upload.upload_id, parts)
+            .await?;
+        if res.status_code() != 200 {
+            error!(
+                "storage backend responded status code {} completing multipart upload",
+                res.status_code()
+            );
+            anyhow::bail!("error uploading image to cdn")
+        }
+        abort_guard.upload_id = None;
+        Ok(())
+    }
+
+    // progress updates go through a channel to a task that keeps `uploads` up to date.
+    // once the sender is dropped (upload done, failed or cancelled) the entry goes away
+    fn track_upload(&self, path: &str, total_bytes: u64) -> mpsc::Sender<UploadProgress> {
+        let (tx, mut rx) = mpsc::channel(16);
+        let id = Uuid::new_v4();
+        self.uploads.insert(
+            id,
+            (
+                path.to_string(),
+                UploadProgress {
+                    bytes_sent: 0,
+                    total_bytes,
+                },
+            ),
+        );
+
+        let uploads = self.uploads.clone();
+        tokio::spawn(async move {
+            while let Some(progress) = rx.recv().await {
+                if let Some(mut entry) = uploads.get_mut(&id) {
+                    entry.1 = progress;
+                }
+            }
+            uploads.remove(&id);
+        });
+        tx
+    }
+
+    pub fn upload_progress(&self, id: &Uuid) -> Option<(String, UploadProgress)> {
+        self.uploads.get(id).map(|x| x.value().clone())
+    }
+
+    pub fn uploads_in_progress(&self) -> Vec<(Uuid, String, UploadProgress)> {
+        self.uploads
+            .iter()
+            .map(|x| (*x.key(), x.value().0.clone(), x.value().1))
+            .collect()
+    }
+}
+
+struct AbortUploadOnDrop {
+    bucket: s3::Bucket,
+    path: String,
+    // None once the upload has completed
+    upload_id: Option<String>,
+}
+
+impl Drop for AbortUploadOnDrop {
+    fn drop(&mut self) {
+        if let Some(upload_id) = self.upload_id.take() {
+            let bucket = self.bucket.clone();
+            let path = std::mem::take(&mut self.path);
+            tokio::spawn(async move {
+                if let Err(e) = bucket.abort_upload(&path, &upload_id).await {
+                    error!("error aborting multipart upload for {}: {}", path, e);
+                }
+            });
+        }
+    }
 }
@@ -0,0 +1,117 @@
+use crate::PKAvatarError;
+use libwebp_sys::*;
+use std::ffi::CStr;
+
+// animated webp encoding straight on top of libwebp. the `webp` crate's `AnimEncoder` holds on to
+// every frame until the end, this hands each one to libwebp as soon as we have it, which only keeps
+// the previous canvas and the encoded output around
+pub struct AnimEncoder {
+    encoder: *mut WebPAnimEncoder,
+    config: WebPConfig,
+    width: u32,
+    height: u32,
+}
+
+impl AnimEncoder {
+    // loop_count 0 = forever
+    pub fn new(width: u32, height: u32, quality: f32, loop_count: i32) -> Result<AnimEncoder, PKAvatarError> {
+        let mut config = WebPConfig::new().map_err(|_| internal_error("error creating webp config"))?;
+        config.quality = quality;
+
+        let mut options = std::mem::MaybeUninit::<WebPAnimEncoderOptions>::uninit();
+        // safety: options only gets read once libwebp has initialized it
+        let encoder = unsafe {
+            if WebPAnimEncoderOptionsInitInternal(options.as_mut_ptr(), WebPGetMuxABIVersion()) == 0 {
+                return Err(internal_error("error creating webp animation options"));
+            }
+            let mut options = options.assume_init();
+            options.anim_params.loop_count = loop_count;
+            WebPAnimEncoderNewInternal(width as i32, height as i32, &options, WebPGetMuxABIVersion())
+        };
+        if encoder.is_null() {
+            return Err(internal_error("error creating webp animation encoder"));
+        }
+
+        Ok(AnimEncoder {
+            encoder,
+            config,
+            width,
+            height,
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    // `timestamp_ms` is when the frame starts showing. the frame is encoded right away,
+    // the caller can drop its pixels once this returns
+    pub fn add_frame(&mut self, rgba: &[u8], timestamp_ms: i32) -> Result<(), PKAvatarError> {
+        if rgba.len() != self.width as usize * self.height as usize * 4 {
+            return Err(internal_error("animation frame doesn't match the canvas size"));
+        }
+
+        let mut picture = WebPPicture::new().map_err(|_| internal_error("error creating webp picture"))?;
+        picture.use_argb = 1;
+        picture.width = self.width as i32;
+        picture.height = self.height as i32;
+
+        // safety: the buffer is checked to be width * height rgba pixels above, and the
+        // picture's own copy of it is freed on every path out of here
+        unsafe {
+            if WebPPictureImportRGBA(&mut picture, rgba.as_ptr(), self.width as i32 * 4) == 0 {
+                WebPPictureFree(&mut picture);
+                return Err(internal_error("error importing animation frame"));
+            }
+            let ok = WebPAnimEncoderAdd(self.encoder, &mut picture, timestamp_ms, &self.config);
+            WebPPictureFree(&mut picture);
+            if ok == 0 {
+                return Err(self.error("error encoding animation frame"));
+            }
+        }
+        Ok(())
+    }
+
+    // `end_timestamp_ms` is when the last frame stops showing, i.e. its timestamp plus its duration
+    pub fn finish(self, end_timestamp_ms: i32) -> Result<Vec<u8>, PKAvatarError> {
+        // safety: the null frame is how libwebp's api marks the end of the animation,
+        // and the assembled data is copied out before libwebp's allocation is freed
+        unsafe {
+            if WebPAnimEncoderAdd(self.encoder, std::ptr::null_mut(), end_timestamp_ms, std::ptr::null()) == 0 {
+                return Err(self.error("error finishing animation"));
+            }
+
+            let mut data = WebPData::default();
+            if WebPAnimEncoderAssemble(self.encoder, &mut data) == 0 {
+                return Err(self.error("error assembling animation"));
+            }
+            let encoded = std::slice::from_raw_parts(data.bytes, data.size).to_vec();
+            WebPDataClear(&mut data);
+            Ok(encoded)
+        }
+    }
+
+    fn error(&self, context: &str) -> PKAvatarError {
+        // safety: libwebp owns the string, it stays valid until the encoder is deleted
+        let message = unsafe {
+            let message = WebPAnimEncoderGetError(self.encoder);
+            if message.is_null() {
+                "unknown error".into()
+            } else {
+                CStr::from_ptr(message).to_string_lossy()
+            }
+        };
+        internal_error(&format!("{}: {}", context, message))
+    }
+}
+
+impl Drop for AnimEncoder {
+    fn drop(&mut self) {
+        // safety: only ever created non-null in `new`, and this is the only place it's deleted
+        unsafe { WebPAnimEncoderDelete(self.encoder) }
+    }
+}
+
+fn internal_error(message: &str) -> PKAvatarError {
+    PKAvatarError::InternalError(anyhow::anyhow!("{}", message))
+}